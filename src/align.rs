@@ -0,0 +1,301 @@
+
+use std::collections::HashMap;
+use bio::data_structures::suffix_array::suffix_array;
+use bio::alphabets::dna;
+
+// Alignment of a read anchor against the reference genome. The position
+// is the 1-based leftmost position of the alignment, regardless of strand.
+#[derive(Debug, Clone)]
+pub struct Alignment {
+	pub chr: String,
+	pub pos: usize,
+	pub strand: bool,
+	pub mismatches: usize
+}
+
+// Occurrence counts are sampled every OCC_INTERVAL rows of the BWT, and
+// suffix array values at every SA_INTERVAL positions of the text.
+const OCC_INTERVAL: usize = 64;
+const SA_INTERVAL: usize = 32;
+const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+
+// FM-index built over the concatenated reference genome. Chromosomes are
+// separated by sentinel characters, and any non-ACGT nucleotides are
+// converted to N so that anchors can never align across them.
+//
+// Only a sample of the suffix array is kept in memory. A row is sampled
+// if its text position is a multiple of SA_INTERVAL, or if it is preceded
+// by a sentinel or N in the text (so that its BWT character is not a base).
+// Other rows are located by stepping backwards through the text with the
+// LF mapping until a sampled row is reached.
+pub struct FMIndex {
+	bwt: Vec<u8>,
+	occ: Vec<[u32; 4]>,      // Counts of ACGT in bwt[..k * OCC_INTERVAL]
+	less: [usize; 4],        // Number of characters smaller than ACGT
+	sampled: Vec<u64>,       // Bit vector of sampled suffix array rows
+	sampled_rank: Vec<u32>,  // Number of sampled rows before each word
+	sa_samples: Vec<u32>,    // Text positions of the sampled rows
+	chr_names: Vec<String>,
+	chr_starts: Vec<usize>   // Offset of each chromosome in the text
+}
+
+fn base_index(nuc: u8) -> Option<usize> {
+	match nuc {
+		b'A' => Some(0), b'C' => Some(1), b'G' => Some(2), b'T' => Some(3),
+		_ => None
+	}
+}
+
+impl FMIndex {
+	// Builds the index. Returns an error if the genome is too large for
+	// the 32-bit positions stored in the index.
+	pub fn new(genome: &HashMap<String, Vec<u8>>) -> Result<FMIndex, String> {
+		let mut chr_names: Vec<String> = genome.keys().cloned().collect();
+		chr_names.sort();
+
+		let mut text: Vec<u8> = Vec::new();
+		let mut chr_starts: Vec<usize> = Vec::new();
+		for chr in &chr_names {
+			chr_starts.push(text.len());
+			for nuc in &genome[chr] {
+				let nuc = nuc.to_ascii_uppercase();
+				text.push(if base_index(nuc).is_some() { nuc } else { b'N' });
+			}
+			text.push(b'$');
+		}
+		if text.len() > u32::MAX as usize {
+			return Err("Genome is too large for the built-in anchor aligner.".into());
+		}
+
+		// The full suffix array is only needed while we construct the BWT
+		// and the suffix array samples, and is dropped afterwards.
+		let sa = suffix_array(&text);
+		let mut bwt: Vec<u8> = Vec::with_capacity(sa.len());
+		let mut sampled: Vec<u64> = vec![0; sa.len() / 64 + 1];
+		let mut sa_samples: Vec<u32> = Vec::new();
+		for (row, &p) in sa.iter().enumerate() {
+			let prev = if p > 0 { text[p - 1] } else { text[text.len() - 1] };
+			bwt.push(prev);
+			if p % SA_INTERVAL == 0 || base_index(prev).is_none() {
+				sampled[row / 64] |= 1 << (row % 64);
+				sa_samples.push(p as u32);
+			}
+		}
+		drop(sa);
+
+		let mut sampled_rank: Vec<u32> = Vec::with_capacity(sampled.len());
+		let mut rank = 0;
+		for word in &sampled {
+			sampled_rank.push(rank);
+			rank += word.count_ones();
+		}
+
+		let mut occ: Vec<[u32; 4]> = Vec::with_capacity(bwt.len() / OCC_INTERVAL + 1);
+		let mut counts = [0u32; 4];
+		for (k, nuc) in bwt.iter().enumerate() {
+			if k % OCC_INTERVAL == 0 { occ.push(counts); }
+			if let Some(b) = base_index(*nuc) { counts[b] += 1; }
+		}
+		occ.push(counts);
+
+		// Suffixes are sorted by byte value, so characters smaller than
+		// each base include the sentinels and (for T) the N characters.
+		let mut less = [0usize; 4];
+		for (b, base) in BASES.iter().enumerate() {
+			less[b] = text.iter().filter(|c| **c < *base).count();
+		}
+
+		Ok(FMIndex { bwt, occ, less, sampled, sampled_rank, sa_samples,
+			chr_names, chr_starts })
+	}
+
+	// Number of occurrences of base #b in bwt[..row].
+	fn occ(&self, row: usize, b: usize) -> usize {
+		let checkpoint = row / OCC_INTERVAL;
+		let mut count = self.occ[checkpoint][b] as usize;
+		for nuc in &self.bwt[checkpoint * OCC_INTERVAL..row] {
+			if *nuc == BASES[b] { count += 1; }
+		}
+		count
+	}
+
+	// Backtracking backward search that extends the suffix array interval
+	// (lo, hi) with the pattern, allowing up to max_mismatches substitutions.
+	// Each resulting interval is reported with its number of mismatches.
+	fn backward_search(&self, pattern: &[u8], (lo, hi): (usize, usize),
+		max_mismatches: usize, mismatches: usize,
+		intervals: &mut Vec<(usize, usize, usize)>) {
+
+		let nuc = match pattern.last() {
+			Some(nuc) => *nuc,
+			None => { intervals.push((lo, hi, mismatches)); return; }
+		};
+		for (b, base) in BASES.iter().enumerate() {
+			// Ambiguous nucleotides in the anchor always count as mismatches
+			let mm = mismatches + (nuc != *base) as usize;
+			if mm > max_mismatches { continue; }
+			let new_lo = self.less[b] + self.occ(lo, b);
+			let new_hi = self.less[b] + self.occ(hi, b);
+			if new_lo >= new_hi { continue; }
+			self.backward_search(&pattern[..pattern.len() - 1],
+				(new_lo, new_hi), max_mismatches, mm, intervals);
+		}
+	}
+
+	// Text position of the suffix at the given row of the suffix array.
+	fn suffix_position(&self, mut row: usize) -> usize {
+		let mut steps = 0;
+		loop {
+			let (word, bit) = (row / 64, row % 64);
+			let bits = self.sampled[word];
+			if bits & (1 << bit) != 0 {
+				let rank = self.sampled_rank[word] as usize +
+					(bits & ((1 << bit) - 1)).count_ones() as usize;
+				return self.sa_samples[rank] as usize + steps;
+			}
+			// Unsampled rows are always preceded by a base in the text
+			let b = base_index(self.bwt[row]).unwrap();
+			row = self.less[b] + self.occ(row, b);
+			steps += 1;
+		}
+	}
+
+	fn locate(&self, row: usize, strand: bool, mismatches: usize) -> Alignment {
+		let offset = self.suffix_position(row);
+		let c = match self.chr_starts.binary_search(&offset) {
+			Ok(c) => c, Err(c) => c - 1
+		};
+		Alignment {
+			chr: self.chr_names[c].clone(),
+			pos: offset - self.chr_starts[c] + 1, strand, mismatches
+		}
+	}
+
+	// Aligns an anchor sequence against both strands of the genome, allowing
+	// up to max_mismatches substitutions. Returns an empty vector if the
+	// anchor aligns to more than max_hits locations.
+	pub fn align(&self, anchor: &[u8], max_mismatches: usize, max_hits: usize)
+		-> Vec<Alignment> {
+
+		let revcomp = dna::revcomp(anchor);
+		let mut fwd: Vec<(usize, usize, usize)> = Vec::new();
+		let mut rev: Vec<(usize, usize, usize)> = Vec::new();
+		let n = self.bwt.len();
+		self.backward_search(anchor, (0, n), max_mismatches, 0, &mut fwd);
+		self.backward_search(&revcomp, (0, n), max_mismatches, 0, &mut rev);
+
+		let num_hits: usize = fwd.iter().chain(rev.iter())
+			.map(|(lo, hi, _)| hi - lo).sum();
+		if num_hits > max_hits { return Vec::new(); }
+
+		let mut hits: Vec<Alignment> = Vec::new();
+		for (lo, hi, mm) in fwd {
+			for row in lo..hi { hits.push(self.locate(row, true, mm)); }
+		}
+		for (lo, hi, mm) in rev {
+			for row in lo..hi { hits.push(self.locate(row, false, mm)); }
+		}
+		hits
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::common::test_util;
+
+	// Two chromosomes, the second of which contains a run of N characters
+	fn test_genome() -> HashMap<String, Vec<u8>> {
+		let mut genome = test_util::test_genome(3000);
+		for nuc in &mut genome.get_mut("chr2").unwrap()[1000..1010] { *nuc = b'N'; }
+		genome
+	}
+
+	fn hits(alignments: &[Alignment]) -> Vec<(&str, usize, bool, usize)> {
+		let mut hits: Vec<(&str, usize, bool, usize)> = alignments.iter()
+			.map(|a| (a.chr.as_str(), a.pos, a.strand, a.mismatches)).collect();
+		hits.sort();
+		hits
+	}
+
+	#[test]
+	fn sampled_suffix_array() {
+		let genome = test_genome();
+		let index = FMIndex::new(&genome).unwrap();
+		let mut text: Vec<u8> = Vec::new();
+		for chr in &["chr1", "chr2"] {
+			text.extend(&genome[*chr]);
+			text.push(b'$');
+		}
+		let sa = suffix_array(&text);
+		for (row, p) in sa.iter().enumerate() {
+			assert_eq!(index.suffix_position(row), *p);
+		}
+	}
+
+	#[test]
+	fn exact_hits() {
+		let genome = test_genome();
+		let index = FMIndex::new(&genome).unwrap();
+		let anchor = &genome["chr1"][100..130];
+		assert_eq!(hits(&index.align(anchor, 0, 10)), vec![("chr1", 101, true, 0)]);
+		let anchor = &genome["chr2"][2500..2530];
+		assert_eq!(hits(&index.align(anchor, 0, 10)), vec![("chr2", 2501, true, 0)]);
+	}
+
+	#[test]
+	fn reverse_strand_hits() {
+		let genome = test_genome();
+		let index = FMIndex::new(&genome).unwrap();
+		let anchor = dna::revcomp(&genome["chr2"][500..530]);
+		assert_eq!(hits(&index.align(&anchor, 0, 10)), vec![("chr2", 501, false, 0)]);
+	}
+
+	#[test]
+	fn hits_with_mismatches() {
+		let genome = test_genome();
+		let index = FMIndex::new(&genome).unwrap();
+		let mut anchor = genome["chr1"][1200..1230].to_vec();
+		for k in &[5, 20] {
+			anchor[*k] = if anchor[*k] == b'A' { b'C' } else { b'A' };
+		}
+		assert!(index.align(&anchor, 1, 10).is_empty());
+		assert_eq!(hits(&index.align(&anchor, 2, 10)), vec![("chr1", 1201, true, 2)]);
+
+		// Ambiguous nucleotides in the anchor count as mismatches
+		anchor[10] = b'N';
+		assert!(index.align(&anchor, 2, 10).is_empty());
+		assert_eq!(hits(&index.align(&anchor, 3, 10)), vec![("chr1", 1201, true, 3)]);
+	}
+
+	#[test]
+	fn max_hits_cutoff() {
+		let mut genome = test_genome();
+		let repeat = genome["chr1"][400..430].to_vec();
+		genome.get_mut("chr1").unwrap()[2000..2030].copy_from_slice(&repeat);
+		genome.get_mut("chr2").unwrap()[100..130]
+			.copy_from_slice(&dna::revcomp(&repeat));
+		let index = FMIndex::new(&genome).unwrap();
+		assert!(index.align(&repeat, 0, 2).is_empty());
+		assert_eq!(hits(&index.align(&repeat, 0, 3)), vec![
+			("chr1", 401, true, 0), ("chr1", 2001, true, 0), ("chr2", 101, false, 0)]);
+	}
+
+	#[test]
+	fn no_hits_across_boundaries() {
+		let genome = test_genome();
+		let index = FMIndex::new(&genome).unwrap();
+
+		// Anchor spanning the end of chr1 and the start of chr2
+		let mut anchor = genome["chr1"][2985..].to_vec();
+		anchor.extend(&genome["chr2"][..15]);
+		assert!(index.align(&anchor, 2, 10).is_empty());
+
+		// Anchor spanning the run of N characters in chr2, with bases in
+		// place of the Ns. Even with enough mismatches allowed to cover
+		// the Ns, the anchor cannot align across them.
+		let mut anchor = genome["chr2"][990..1020].to_vec();
+		for nuc in &mut anchor[10..20] { *nuc = b'A'; }
+		assert!(index.align(&anchor, 10, 10).is_empty());
+	}
+}
@@ -1,99 +1,327 @@
 
-use crate::common::parse_args;
-use std::io::{BufRead, BufReader, stdin};
-use std::fs::File;
-
-const USAGE: &str = "
-Usage:
-  breakfast annotate [options] <sv_path> <bed_path>
-";
-
-fn distance(pos: u32, feature: &Feature) -> u32 {
-	if pos < feature.start {
-		feature.start - pos
-	} else if pos > feature.end {
-		pos - feature.end
+use crate::common::{header_format, line_format};
+use crate::common::{parse_rearrangement, ParseError};
+use crate::common::{FileReader, SvRecord};
+use crate::gtf::Transcript;
+use crate::fusion::{predict_fusion, first_is_5p};
+use std::collections::{HashMap, HashSet};
+use std::cmp::{max, Ordering};
+use std::io;
+
+// Genomic interval with 1-based inclusive coordinates
+pub trait Interval {
+	fn chr(&self) -> &str;
+	fn start(&self) -> u32;
+	fn end(&self) -> u32;
+}
+
+fn distance(pos: u32, feature: &impl Interval) -> u32 {
+	if pos < feature.start() {
+		feature.start() - pos
+	} else if pos > feature.end() {
+		pos - feature.end()
 	} else {
 		0
 	}
 }
 
-struct Feature {
-	chr: String,
-	start: u32,   // 1-based position of first base
-	end: u32,     // 1-based position of last base
-	name: String
-}
-
-pub fn main() {
-	let args = parse_args(USAGE);
-	let sv_path = args.get_str("<sv_path>");
-	let bed_path = args.get_str("<bed_path>");
-
-    let mut sv: Box<BufRead>;
-    if sv_path == "-" {   // TODO: Make a function that handles this.
-    	sv = Box::new(BufReader::new(stdin()));
-    } else {
-    	sv = Box::new(BufReader::new(File::open(&sv_path).unwrap()));
-    }
-
-	let mut header = String::new();
-	sv.read_line(&mut header).unwrap();
-	print!("{}", header);
-
-    let bed = BufReader::new(File::open(&bed_path).unwrap());
-    let mut features: Vec<Feature> = Vec::new();
-    for l in bed.lines() {
-        let line = l.unwrap();
-        let mut cols = line.split('\t');
-        features.push(Feature {
-        	chr: cols.next().unwrap().to_string(),
-        	start: cols.next().unwrap().parse::<u32>().unwrap() + 1,
-        	end: cols.next().unwrap().parse().unwrap(),
-        	name: cols.next().unwrap().to_string()
-        });
-    }
-
-    for l in sv.lines() {
-		let line = l.unwrap();
-		if !line.starts_with("chr") { continue; }
-
-		let cols: Vec<&str> = line.split('\t').collect();
-        let chr_1 = cols[0];
-        let pos_1: u32 = cols[2].parse().unwrap();
-        let chr_2 = cols[4];
-        let pos_2: u32 = cols[6].parse().unwrap();
-        let reads = cols[8];
-
-        let mut nearby_features_1: Vec<(u32, &Feature)> = Vec::new();
-        for feature in &features {
-        	if chr_1 != feature.chr { continue; }
-        	let dist = distance(pos_1, feature);
-        	if dist > 100_000 { continue; }
-        	nearby_features_1.push((dist, feature));
-        }
-        nearby_features_1.sort_by_key(|x| x.0);
-
-        let mut nearby_features_2: Vec<(u32, &Feature)> = Vec::new();
-        for feature in &features {
-        	if chr_2 != feature.chr { continue; }
-        	let dist = distance(pos_2, feature);
-        	if dist > 100_000 { continue; }
-        	nearby_features_2.push((dist, feature));
-        }
-        nearby_features_2.sort_by_key(|x| x.0);
-
-        print!("{}\t{}\t{}\t", chr_1, cols[1], pos_1);
-        for (k, nf) in nearby_features_1.iter().enumerate() {
-        	print!("{} ({})", nf.1.name, nf.0);
-        	if k < nearby_features_1.len() - 1 { print!(", "); }
-        }
-        print!("\t");
-        print!("{}\t{}\t{}\t", chr_2, cols[5], pos_2);
-        for (k, nf) in nearby_features_2.iter().enumerate() {
-        	print!("{} ({})", nf.1.name, nf.0);
-        	if k < nearby_features_2.len() - 1 { print!(", "); }
-        }
-        println!("\t{}\t{}\t{}", reads, cols[9], cols[10]);
+// Genomic feature (typically a gene) read from a BED file
+pub struct Feature {
+	pub chr: String,
+	pub start: u32,            // 1-based position of first base
+	pub end: u32,              // 1-based position of last base
+	pub name: String,
+	pub strand: Option<bool>   // None if the BED file has no strand column
+}
+
+impl Interval for Feature {
+	fn chr(&self) -> &str { &self.chr }
+	fn start(&self) -> u32 { self.start }
+	fn end(&self) -> u32 { self.end }
+}
+
+// Reads genomic features from a BED file with at least four columns. The
+// strand is read from the sixth column, if present.
+pub fn read_features(bed_path: &str) -> Result<Vec<Feature>, ParseError> {
+	let io_error = |err: io::Error| ParseError::io(bed_path, err);
+	let mut bed = FileReader::open(bed_path).map_err(io_error)?;
+	let mut features: Vec<Feature> = Vec::new();
+	let mut line = String::new();
+	let mut line_num = 0;
+	while bed.next_line(&mut line).map_err(io_error)? {
+		line_num += 1;
+		if line.starts_with('#') || line.starts_with("track") ||
+			line.starts_with("browser") || line.trim().is_empty() { continue; }
+		let cols: Vec<&str> = line.trim_end().split('\t').collect();
+		let start = cols.get(1).and_then(|c| c.parse::<u32>().ok());
+		let end = cols.get(2).and_then(|c| c.parse::<u32>().ok());
+		if cols.len() < 4 || start.is_none() || end.is_none() {
+			return Err(ParseError::new(bed_path, line_num,
+				format!("Invalid BED line:\n{}", line)));
+		}
+		features.push(Feature {
+			chr: cols[0].to_string(),
+			start: start.unwrap() + 1,
+			end: end.unwrap(),
+			name: cols[3].to_string(),
+			strand: match cols.get(5) {
+				Some(&"+") => Some(true), Some(&"-") => Some(false), _ => None
+			}
+		});
+	}
+	Ok(features)
+}
+
+// Features of each chromosome sorted by start position. For fast lookups,
+// we also store the largest end position among the features up to each
+// index. Features overlapping a window can then be found by binary
+// searching for the last feature that starts within the window, and
+// scanning backwards until no earlier feature can reach the window.
+pub struct FeatureIndex<T: Interval = Feature> {
+	chromosomes: HashMap<String, (Vec<T>, Vec<u32>)>
+}
+
+impl<T: Interval> FeatureIndex<T> {
+	pub fn new(features: Vec<T>) -> FeatureIndex<T> {
+		let mut chromosomes: HashMap<String, (Vec<T>, Vec<u32>)> =
+			HashMap::new();
+		for feature in features {
+			chromosomes.entry(feature.chr().to_string())
+				.or_insert_with(|| (Vec::new(), Vec::new())).0.push(feature);
+		}
+		for (features, max_end) in chromosomes.values_mut() {
+			features.sort_by_key(|f| f.start());
+			let mut end = 0;
+			for feature in features.iter() {
+				end = max(end, feature.end());
+				max_end.push(end);
+			}
+		}
+		FeatureIndex { chromosomes }
+	}
+
+	// Returns features within max_distance of a position, along with their
+	// distances, in order of start position.
+	pub fn nearby(&self, chr: &str, pos: u32, max_distance: u32)
+		-> Vec<(u32, &T)> {
+		let (features, max_end) = match self.chromosomes.get(chr) {
+			Some(chromosome) => chromosome, None => return Vec::new()
+		};
+		let last = pos.saturating_add(max_distance);
+		let first = pos.saturating_sub(max_distance);
+		let mut k = match features.binary_search_by(
+			|f| if f.start() <= last { Ordering::Less } else { Ordering::Greater }) {
+			Ok(k) | Err(k) => k
+		};
+
+		let mut nearby: Vec<(u32, &T)> = Vec::new();
+		while k > 0 && max_end[k - 1] >= first {
+			k -= 1;
+			let dist = distance(pos, &features[k]);
+			if dist <= max_distance { nearby.push((dist, &features[k])); }
+		}
+		nearby.reverse();
+		nearby
+	}
+}
+
+// Lists features within max_distance of a breakpoint, nearest first.
+pub fn nearby_features(index: &FeatureIndex, chr: &str, pos: u32,
+	max_distance: u32) -> String {
+	let mut nearby = index.nearby(chr, pos, max_distance);
+	nearby.sort_by_key(|x| x.0);
+	nearby.iter().map(|(dist, f)| format!("{} ({})", f.name, dist))
+		.collect::<Vec<String>>().join(", ")
+}
+
+// Finds the transcript and the transcript region (exon, intron or UTR) that
+// a breakpoint lies in. If multiple transcripts overlap the breakpoint,
+// canonical transcripts are preferred, followed by protein coding
+// transcripts and then the transcripts with the longest exonic sequence.
+pub fn breakpoint_location<'a>(index: &'a FeatureIndex<Transcript>, chr: &str,
+	pos: u32) -> Option<(&'a Transcript, String)> {
+	index.nearby(chr, pos, 0).into_iter()
+		.filter_map(|(_, t)| t.region(pos).map(|region| (t, region)))
+		.max_by(|(a, _), (b, _)|
+			(a.canonical, !a.cds.is_empty(), a.exonic_len(), &b.id).cmp(
+			&(b.canonical, !b.cds.is_empty(), b.exonic_len(), &a.id)))
+}
+
+// Describes the location of breakpoint #side within a gene in the notes
+// column, replacing any previous annotation. Returns the transcript that
+// the breakpoint lies in.
+pub fn annotate_location<'a>(sv: &mut SvRecord, side: usize,
+	index: &'a FeatureIndex<Transcript>) -> Option<&'a Transcript> {
+	let (chr, pos) = if side == 1 { (&sv.chr, sv.pos) } else { (&sv.mchr, sv.mpos) };
+	let location = breakpoint_location(index, chr, pos as u32);
+	match location {
+		Some((t, ref region)) => {
+			let (gene, id, strand) = (t.gene.clone(), t.id.clone(), t.strand);
+			sv.set_note(&format!("REGION_{}", side), region);
+			sv.set_note(&format!("GENE_{}", side), &gene);
+			sv.set_note(&format!("TRANSCRIPT_{}", side), &id);
+			sv.set_note(&format!("GENE_STRAND_{}", side),
+				if strand { "+" } else { "-" });
+		},
+		None => {
+			sv.set_note(&format!("REGION_{}", side), "intergenic");
+			for key in &["GENE", "TRANSCRIPT", "GENE_STRAND"] {
+				sv.remove_note(&format!("{}_{}", key, side));
+			}
+		}
+	}
+	location.map(|(t, _)| t)
+}
+
+// Describes the gene fusion that a rearrangement between two transcripts
+// would produce in the notes column, replacing any previous prediction.
+// FUSION lists the 5' and 3' partner genes (or incompatible_orientation),
+// FRAME tells whether the fused coding sequence is in-frame, and PEPTIDE
+// is the predicted fusion protein around the junction (marked with '|').
+pub fn annotate_fusion(sv: &mut SvRecord, transcripts: (Option<&Transcript>,
+	Option<&Transcript>), genome: &HashMap<String, Vec<u8>>) {
+	for key in &["FUSION", "FRAME", "PEPTIDE"] { sv.remove_note(key); }
+	let (t1, t2) = match transcripts {
+		(Some(t1), Some(t2)) => (t1, t2),
+		_ => return
+	};
+	match predict_fusion(sv, t1, t2, genome) {
+		Some(fusion) => {
+			sv.set_note("FUSION", &format!("{}>{}", fusion.gene_5p, fusion.gene_3p));
+			sv.set_note("FRAME", fusion.frame);
+			if let Some(peptide) = fusion.peptide {
+				sv.set_note("PEPTIDE", &peptide);
+			}
+		},
+		None => sv.set_note("FUSION", "incompatible_orientation")
+	}
+}
+
+// Reads known fusion gene pairs from a tab-delimited file, with the 5' and
+// 3' partner genes in the first two columns. A pair can also be given in the
+// first column alone, as GENE1::GENE2 or GENE1--GENE2. Any further columns
+// and lines starting with '#' are ignored.
+pub fn read_known_fusions(path: &str)
+	-> Result<HashSet<(String, String)>, ParseError> {
+	let io_error = |err: io::Error| ParseError::io(path, err);
+	let mut file = FileReader::open(path).map_err(io_error)?;
+	let mut fusions: HashSet<(String, String)> = HashSet::new();
+	let mut line = String::new();
+	let mut line_num = 0;
+	while file.next_line(&mut line).map_err(io_error)? {
+		line_num += 1;
+		if line.starts_with('#') || line.trim().is_empty() { continue; }
+		let cols: Vec<&str> = line.trim_end().split('\t').collect();
+		let pair = ["::", "--"].iter().filter_map(|sep| {
+			cols[0].find(sep).map(|k| (&cols[0][..k], &cols[0][k + sep.len()..]))
+		}).next().or_else(|| cols.get(1).map(|gene_3p| (cols[0], *gene_3p)));
+		match pair {
+			Some((gene_5p, gene_3p)) if !gene_5p.is_empty() && !gene_3p.is_empty() =>
+				fusions.insert((gene_5p.to_string(), gene_3p.to_string())),
+			_ => return Err(ParseError::new(path, line_num,
+				format!("Invalid fusion gene pair:\n{}", line)))
+		};
+	}
+	Ok(fusions)
+}
+
+// Reads gene names from the first column of a file. Lines starting with '#'
+// are ignored.
+pub fn read_gene_list(path: &str) -> Result<HashSet<String>, ParseError> {
+	let io_error = |err: io::Error| ParseError::io(path, err);
+	let mut file = FileReader::open(path).map_err(io_error)?;
+	let mut genes: HashSet<String> = HashSet::new();
+	let mut line = String::new();
+	while file.next_line(&mut line).map_err(io_error)? {
+		if line.starts_with('#') || line.trim().is_empty() { continue; }
+		genes.insert(line.trim_end().split('\t').next().unwrap().to_string());
+	}
+	Ok(genes)
+}
+
+// Tags a rearrangement in the notes column if the features (genes) that
+// its breakpoints lie in form a known fusion (KNOWN_FUSION, as 5'>3') or
+// include cancer genes (CANCER_GENES). Known fusions only match if the
+// partner genes are oriented so that the first gene of the pair is the 5'
+// partner, which requires the strands of both genes.
+pub fn annotate_known_genes(sv: &mut SvRecord, index: &FeatureIndex,
+	known_fusions: Option<&HashSet<(String, String)>>,
+	cancer_genes: Option<&HashSet<String>>) {
+	let genes_1: Vec<&Feature> = index.nearby(&sv.chr, sv.pos as u32, 0)
+		.into_iter().map(|(_, f)| f).collect();
+	let genes_2: Vec<&Feature> = index.nearby(&sv.mchr, sv.mpos as u32, 0)
+		.into_iter().map(|(_, f)| f).collect();
+
+	if let Some(known_fusions) = known_fusions {
+		let mut matches: Vec<String> = Vec::new();
+		for f1 in &genes_1 {
+			for f2 in &genes_2 {
+				if f1.name == f2.name { continue; }
+				let pair = match (f1.strand, f2.strand) {
+					(Some(strand_1), Some(strand_2)) =>
+						match first_is_5p(sv, strand_1, strand_2) {
+						Some(true) => (&f1.name, &f2.name),
+						Some(false) => (&f2.name, &f1.name),
+						None => continue
+					},
+					_ => continue
+				};
+				let fusion = format!("{}>{}", pair.0, pair.1);
+				if known_fusions.contains(&(pair.0.clone(), pair.1.clone())) &&
+					!matches.contains(&fusion) {
+					matches.push(fusion);
+				}
+			}
+		}
+		sv.remove_note("KNOWN_FUSION");
+		if !matches.is_empty() { sv.set_note("KNOWN_FUSION", &matches.join(",")); }
+	}
+
+	if let Some(cancer_genes) = cancer_genes {
+		let mut matches: Vec<&str> = Vec::new();
+		for f in genes_1.iter().chain(genes_2.iter()) {
+			if cancer_genes.contains(&f.name) && !matches.contains(&f.name.as_str()) {
+				matches.push(&f.name);
+			}
+		}
+		sv.remove_note("CANCER_GENES");
+		if !matches.is_empty() { sv.set_note("CANCER_GENES", &matches.join(",")); }
+	}
+}
+
+// Columns holding the nearby features of both breakpoints, and the notes,
+// in each rearrangement file format.
+pub fn annotation_columns(format: &str) -> [usize; 3] {
+	if format == "sv" { [3, 7, 10] } else { [10, 11, 14] }
+}
+
+// Line of a rearrangement file, along with its format (sv or bedpe) and the
+// parsed rearrangement if the line contains one
+pub type AnnotationLine = (String, Option<(&'static str, SvRecord)>);
+
+// Reads the lines of a rearrangement file, parsing the lines that contain
+// rearrangements. Header and comment lines are kept as they are.
+pub fn read_annotation_lines(path: &str)
+	-> Result<Vec<AnnotationLine>, ParseError> {
+	let io_error = |err: io::Error| ParseError::io(path, err);
+	let mut lines = Vec::new();
+	let mut file = FileReader::open(path).map_err(io_error)?;
+	let mut line = String::new();
+	let mut line_num = 0;
+	let mut format = "";
+	while file.next_line(&mut line).map_err(io_error)? {
+		line_num += 1;
+		let line = line.trim_end_matches(&['\n', '\r'][..]).to_string();
+		let header = header_format(&line);
+		if let Some(header) = header { format = header; }
+		if header.is_some() || line.is_empty() || line.starts_with('#') {
+			lines.push((line, None));
+			continue;
+		}
+		let line_format = line_format(&line, format);
+		let sv = parse_rearrangement(&line, line_format)
+			.map_err(|message| ParseError::new(path, line_num, message))?;
+		lines.push((line, Some((line_format, sv))));
 	}
+	Ok(lines)
 }
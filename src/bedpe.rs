@@ -0,0 +1,63 @@
+
+use crate::common::{SvRecord, parse_chromosome, parse_strand};
+use crate::common::{parse_position, parse_reads};
+use std::io;
+use std::io::Write;
+
+pub const HEADER: &str = "#chrom1\tstart1\tend1\tchrom2\tstart2\tend2\tname\tscore\tstrand1\tstrand2\tfeatures1\tfeatures2\tsupporting_reads\tsignature\tnotes";
+
+// BEDPE strands follow the convention used by most other structural variant
+// callers: a '+' strand means that the DNA segment joined at the junction
+// lies to the left of the breakpoint, on both sides. A deletion is therefore
+// reported as +/- and a tandem duplication as -/+. Columns that have no
+// BEDPE equivalent are written after the ten standard columns.
+
+// Parses a rearrangement from a BEDPE line written by write_bedpe().
+// Returns a description of the problem if the line is malformed.
+pub fn from_line(line: &str) -> Result<SvRecord, String> {
+	let cols: Vec<&str> = line.trim_end_matches(&['\n', '\r'][..])
+		.split('\t').collect();
+	if cols.len() < 10 {
+		return Err(format!("Expected at least 10 BEDPE columns, found {}.", cols.len()));
+	}
+	let col = |k: usize| cols.get(k).unwrap_or(&"").to_string();
+	let sv = SvRecord {
+		chr: parse_chromosome(cols[0])?,
+		strand: parse_strand(cols[8])?,
+		pos: parse_position(cols[2])?,
+		features: col(10),
+		mchr: parse_chromosome(cols[3])?,
+		mstrand: !parse_strand(cols[9])?,
+		mpos: parse_position(cols[5])?,
+		mfeatures: col(11),
+		reads: parse_reads(&col(12)),
+		signature: col(13),
+		notes: col(14),
+		extra: cols.iter().skip(15).map(|c| c.to_string()).collect()
+	};
+	sv.validate_sequences()?;
+	Ok(sv)
+}
+
+// Writes rearrangements in BEDPE format. Each breakpoint is written as a
+// 1 bp interval. The score column contains the number of supporting reads.
+pub fn write_bedpe(out: &mut impl Write, extra_header: &[String],
+	rearrangements: &[SvRecord]) -> io::Result<()> {
+
+	write!(out, "{}", HEADER)?;
+	for col in extra_header { write!(out, "\t{}", col)?; }
+	writeln!(out)?;
+
+	for (k, sv) in rearrangements.iter().enumerate() {
+		write!(out, "{}\t{}\t{}\t{}\t{}\t{}\tsv_{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+			sv.chr, sv.pos - 1, sv.pos, sv.mchr, sv.mpos - 1, sv.mpos,
+			k + 1, sv.reads.len(),
+			if sv.strand { '+' } else { '-' },
+			if sv.mstrand { '-' } else { '+' },
+			sv.features, sv.mfeatures, sv.reads.join(";"), sv.signature,
+			sv.notes)?;
+		for col in &sv.extra { write!(out, "\t{}", col)?; }
+		writeln!(out)?;
+	}
+	Ok(())
+}
@@ -0,0 +1,106 @@
+use crate::cli::{parse_args, read_genome, create_output, close_output};
+use breakfast::annotate::{FeatureIndex, read_features, nearby_features};
+use breakfast::annotate::{annotate_location, annotate_fusion, annotate_known_genes};
+use breakfast::annotate::{read_known_fusions, read_gene_list};
+use breakfast::annotate::{annotation_columns, read_annotation_lines};
+use breakfast::gtf::{is_gene_model_file, read_transcripts, gene_features};
+use std::collections::HashMap;
+use std::io::Write;
+
+const USAGE: &str = "
+Usage:
+  breakfast annotate [options] <sv_path> <features_path>
+
+Options:
+  --max-distance=N  Report features within N bp of each breakpoint
+                    [default: 100000]
+  --fusions         Predict gene fusions and their reading frame (requires
+                    GTF/GFF3 gene models and --genome)
+  --genome=PATH     Genome FASTA file, used for fusion junction peptides
+  --known-fusions=PATH  Tag rearrangements matching known fusion gene pairs
+                    listed in a TSV file (5' gene, 3' gene)
+  --cancer-genes=PATH   Tag rearrangements with breakpoints in the cancer
+                    genes listed in a file (one gene per line)
+  --output=PATH     Output file, compressed if it ends in .gz [default: -]
+";
+
+pub fn main() {
+	let args = parse_args(USAGE);
+	let sv_path = args.get_str("<sv_path>");
+	let features_path = args.get_str("<features_path>");
+	let max_distance: u32 = args.get_str("--max-distance").parse()
+		.unwrap_or_else(|_| error!("--max-distance must be numeric."));
+	let fusions = args.get_bool("--fusions");
+	let genome_path = args.get_str("--genome");
+	let known_fusions_path = args.get_str("--known-fusions");
+	let cancer_genes_path = args.get_str("--cancer-genes");
+
+	let mut lines = read_annotation_lines(sv_path)
+		.unwrap_or_else(|err| error!("{}", err));
+
+	// Gene models are used both for listing nearby genes, and for
+	// describing where within each gene the breakpoints lie.
+	let gene_models = is_gene_model_file(features_path).unwrap_or_else(
+		|_| error!("Cannot open file {} for reading.", features_path));
+	let transcripts = if gene_models {
+		read_transcripts(features_path).unwrap_or_else(|err| error!("{}", err))
+	} else { Vec::new() };
+	let features = if gene_models {
+		gene_features(&transcripts)
+	} else {
+		read_features(features_path).unwrap_or_else(|err| error!("{}", err))
+	};
+	if !known_fusions_path.is_empty() && features.iter().any(|f| f.strand.is_none()) {
+		eprintln!("WARNING: Some features in {} have no strand, and will not match any known fusions.", features_path);
+	}
+	let index = FeatureIndex::new(features);
+	let transcript_index = FeatureIndex::new(transcripts);
+
+	if fusions && (!gene_models || genome_path.is_empty()) {
+		error!("--fusions requires GTF/GFF3 gene models and --genome.");
+	}
+	let genome = if fusions { read_genome(genome_path) } else { HashMap::new() };
+	let known_fusions = if known_fusions_path.is_empty() { None } else {
+		Some(read_known_fusions(known_fusions_path)
+			.unwrap_or_else(|err| error!("{}", err)))
+	};
+	let cancer_genes = if cancer_genes_path.is_empty() { None } else {
+		Some(read_gene_list(cancer_genes_path)
+			.unwrap_or_else(|err| error!("{}", err)))
+	};
+	let edit_notes = gene_models || known_fusions.is_some() ||
+		cancer_genes.is_some();
+
+	let mut out = create_output(args.get_str("--output"));
+	for (line, record) in &mut lines {
+		let (format, sv) = match record {
+			Some((format, sv)) => (*format, sv),
+			None => { writeln!(out, "{}", line).unwrap(); continue; }
+		};
+		if gene_models {
+			let t1 = annotate_location(sv, 1, &transcript_index);
+			let t2 = annotate_location(sv, 2, &transcript_index);
+			if fusions { annotate_fusion(sv, (t1, t2), &genome); }
+		}
+		if known_fusions.is_some() || cancer_genes.is_some() {
+			annotate_known_genes(sv, &index, known_fusions.as_ref(),
+				cancer_genes.as_ref());
+		}
+		sv.features = nearby_features(&index, &sv.chr, sv.pos as u32,
+			max_distance);
+		sv.mfeatures = nearby_features(&index, &sv.mchr, sv.mpos as u32,
+			max_distance);
+
+		// Only the annotation columns are rewritten, so that any other
+		// columns are passed through exactly as they were
+		let mut cols: Vec<&str> = line.split('\t').collect();
+		let [features_col, mfeatures_col, notes_col] = annotation_columns(format);
+		let last_col = if edit_notes { notes_col } else { mfeatures_col };
+		if cols.len() <= last_col { cols.resize(last_col + 1, ""); }
+		cols[features_col] = &sv.features;
+		cols[mfeatures_col] = &sv.mfeatures;
+		if edit_notes { cols[notes_col] = &sv.notes; }
+		writeln!(out, "{}", cols.join("\t")).unwrap();
+	}
+	close_output(out);
+}
@@ -1,6 +1,8 @@
 
-use crate::common::{parse_args, FileReader};
+use crate::cli::{parse_args, read_rearrangements};
+use crate::cli::{create_output, close_output};
 use std::collections::HashMap;
+use std::io::Write;
 
 const USAGE: &str = "
 Usage:
@@ -8,6 +10,7 @@ Usage:
 
 Options:
   --min-samples=N    Blacklist if present in N or more samples [default: 1]
+  --output=PATH      Output file, compressed if it ends in .gz [default: -]
 ";
 
 pub fn main() {
@@ -20,20 +23,19 @@ pub fn main() {
     let mut rearrangements: HashMap<String, Vec<bool>> = HashMap::new();
 
     for (s, sv_path) in sv_paths.iter().enumerate() {
-        let mut sv_file = FileReader::new(&sv_path);
-        let mut line = String::new();
-        sv_file.read_line(&mut line);   // Skip the header
-        while sv_file.read_line(&mut line) {
-            let signature = line.split('\t').nth(9).unwrap().to_string();
-            let entry = rearrangements.entry(signature)
+        let (_, svs) = read_rearrangements(sv_path);
+        for sv in svs {
+            let entry = rearrangements.entry(sv.signature)
             	.or_insert_with(|| vec![false; sv_paths.len()]);
             entry[s] = true;
         }
     }
 
+    let mut out = create_output(args.get_str("--output"));
     for (signature, evidence) in rearrangements.iter() {
-    	if evidence.iter().filter(|x| **x == true).count() >= min_samples {
-    		println!("{}", signature);
+    	if evidence.iter().filter(|x| **x).count() >= min_samples {
+    		writeln!(out, "{}", signature).unwrap();
     	}
     }
+    close_output(out);
 }
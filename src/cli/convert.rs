@@ -0,0 +1,31 @@
+
+use crate::cli::{parse_args, read_rearrangements, read_genome};
+use crate::cli::write_output;
+
+const USAGE: &str = "
+Usage:
+  breakfast convert [options] <sv_file>
+
+Options:
+  --format=FORMAT   Output format (vcf, bedpe or sv) [default: vcf]
+  --genome=PATH     Genome FASTA file, used for looking up reference bases
+  --output=PATH     Output file, compressed if it ends in .gz [default: -]
+";
+
+pub fn main() {
+	let args = parse_args(USAGE);
+	let sv_path = args.get_str("<sv_file>");
+	let format = args.get_str("--format");
+	let genome_path = args.get_str("--genome");
+	if format != "sv" && format != "vcf" && format != "bedpe" {
+		error!("Unknown output format '{}'.", format);
+	}
+
+	let genome = if genome_path.is_empty() { None } else {
+		Some(read_genome(genome_path))
+	};
+
+	let (extra_header, rearrangements) = read_rearrangements(sv_path);
+	write_output(args.get_str("--output"), format, &extra_header,
+		&rearrangements, genome.as_ref());
+}
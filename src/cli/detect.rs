@@ -0,0 +1,72 @@
+use crate::cli::{parse_args, read_genome, write_output};
+use breakfast::detect::{Settings, call_rearrangements};
+use breakfast::detect::{align_anchors_with_bowtie, align_anchors_with_fmindex};
+
+const USAGE: &str = "
+Usage:
+  breakfast detect [options] <bam_file> <genome>
+
+Options:
+  --anchor-len=N       Anchor length for split read analysis [default: 30]
+  --anchor-mm=N        Mismatches allowed in anchor alignments [default: 0]
+  --aligner=NAME       Anchor aligner to use (bowtie or builtin) [default: bowtie]
+  --max-placements=N   Maximum genomic placements per anchor [default: 1]
+  --max-frag-len=N     Maximum fragment length [default: 5000]
+  --min-evidence=N     Minimum number of supporting DNA fragments [default: 2]
+  --count-duplicates   Count also reads that have been flagged as duplicates
+  --soft-clipped       Also analyze aligned reads with long soft clipped ends
+  --discordant-pairs   Count discordant read pairs supporting each rearrangement
+  --format=FORMAT      Output format (sv, vcf or bedpe) [default: sv]
+  --output=PATH        Output file, compressed if it ends in .gz [default: -]
+  --reference=PATH     Reference genome FASTA file for reading CRAM files
+                       (default: <genome>.fa)
+";
+
+pub fn main() {
+	let args = parse_args(USAGE);
+	let sam_path = args.get_str("<bam_file>").to_string();
+	let genome_path = args.get_str("<genome>");
+	let anchor_len: usize = args.get_str("--anchor-len").parse().unwrap();
+	let anchor_mm: usize = args.get_str("--anchor-mm").parse().unwrap();
+	let max_frag_len: usize = args.get_str("--max-frag-len").parse().unwrap();
+	let min_evidence: usize = args.get_str("--min-evidence").parse().unwrap();
+	let count_duplicates = args.get_bool("--count-duplicates");
+	let soft_clipped = args.get_bool("--soft-clipped");
+	let discordant_pairs = args.get_bool("--discordant-pairs");
+	let max_placements: usize = args.get_str("--max-placements").parse().unwrap();
+	let aligner = args.get_str("--aligner");
+	let reference = match args.get_str("--reference") {
+		"" => format!("{}.fa", genome_path), path => path.to_string()
+	};
+	let format = args.get_str("--format");
+	if format != "sv" && format != "vcf" && format != "bedpe" {
+		error!("Unknown output format '{}'.", format);
+	}
+	if anchor_mm > 3 { error!("--anchor-mm must be between 0 and 3."); }
+	if max_placements == 0 { error!("--max-placements must be at least 1."); }
+
+	let settings = Settings {
+		anchor_len, anchor_mm, max_placements, max_frag_len, min_evidence,
+		count_duplicates, soft_clipped, discordant_pairs, reference
+	};
+
+	let genome = read_genome(&format!("{}.fa", genome_path));
+
+	eprintln!("Splitting unaligned reads into {} bp anchors and aligning against the genome...", anchor_len);
+	let (evidence, discordant) = if aligner == "bowtie" {
+		align_anchors_with_bowtie(&genome, &settings, &sam_path, genome_path)
+	} else if aligner == "builtin" {
+		align_anchors_with_fmindex(&genome, &settings, &sam_path)
+	} else {
+		error!("Unknown anchor aligner '{}'.", aligner);
+	}.unwrap_or_else(|err| error!("{}", err));
+
+	eprintln!("Found {} rearrangement supporting reads.", evidence.len());
+	if discordant_pairs {
+		eprintln!("Found {} discordant read pairs.", discordant.len());
+	}
+
+	let rearrangements = call_rearrangements(evidence, discordant, &settings);
+	write_output(args.get_str("--output"), format, &[], &rearrangements,
+		Some(&genome));
+}
@@ -0,0 +1,72 @@
+
+use crate::cli::{parse_args, open_file, read_line};
+use crate::cli::{read_rearrangements, write_output};
+use std::collections::HashSet;
+
+const USAGE: &str = "
+Usage:
+  breakfast filter [options] <sv_path>
+
+Options:
+  --min-reads=N     Minimum number of supporting reads [default: 0]
+  --blacklist=PATH  File containing blacklisted breakpoint signatures
+  --type=TYPES      Comma-separated list of event types to keep
+                    (DEL, DUP, INV, INV_TT, INV_HH, TRA)
+  --min-size=N      Minimum event size [default: 0]
+  --max-size=N      Maximum event size (translocations are not kept)
+  --format=FORMAT   Output format (sv or bedpe) [default: sv]
+  --output=PATH     Output file, compressed if it ends in .gz [default: -]
+";
+
+pub fn main() {
+	let args = parse_args(USAGE);
+	let sv_path = args.get_str("<sv_path>");
+	let min_reads: usize = args.get_str("--min-reads").parse().unwrap();
+	let blacklist_path = args.get_str("--blacklist");
+	let types: Vec<&str> = args.get_str("--type").split(',')
+		.filter(|t| !t.is_empty()).collect();
+	let min_size: usize = args.get_str("--min-size").parse()
+		.unwrap_or_else(|_| error!("--min-size must be numeric."));
+	let max_size: Option<usize> = match args.get_str("--max-size") {
+		"" => None,
+		size => Some(size.parse().unwrap_or_else(
+			|_| error!("--max-size must be numeric.")))
+	};
+	let format = args.get_str("--format");
+	if format != "sv" && format != "bedpe" {
+		error!("Unknown output format '{}'.", format);
+	}
+
+	let mut line = String::new();
+
+	let mut blacklist = HashSet::new();
+	if !blacklist_path.is_empty() {
+		let mut bl = open_file(blacklist_path);
+		while read_line(&mut bl, &mut line) {
+			blacklist.insert(line.trim().to_string());
+		}
+	}
+
+	let (extra_header, mut rearrangements) = read_rearrangements(sv_path);
+	rearrangements.retain(|sv| sv.reads.len() >= min_reads &&
+		!blacklist.contains(&sv.signature));
+
+	// Event types can be given either exactly, or as a prefix such as INV
+	// that matches both types of inversions.
+	if !types.is_empty() {
+		rearrangements.retain(|sv| {
+			let sv_type = sv.sv_type();
+			types.iter().any(|t| sv_type == *t ||
+				(sv_type.starts_with(t) && sv_type[t.len()..].starts_with('_')))
+		});
+	}
+
+	// Interchromosomal events have no size, and are treated as infinitely
+	// large.
+	rearrangements.retain(|sv| match sv.size() {
+		Some(size) => size >= min_size && size <= max_size.unwrap_or(size),
+		None => max_size.is_none()
+	});
+	write_output(args.get_str("--output"), format, &extra_header,
+		&rearrangements, None);
+}
@@ -0,0 +1,231 @@
+use crate::cli::{parse_args, open_file, read_line};
+use crate::cli::{read_rearrangements, write_output};
+use breakfast::common::SvRecord;
+use breakfast::matrix::{Settings, Rearrangement, JUNCTION, REF_1, APPROX, ALIGNED};
+use breakfast::matrix::{seed_offsets, count_rearrangements, sample_name};
+use breakfast::matrix::{most_frequent, reverse_complement, parse_ref_signature};
+use breakfast::matrix::allele_fraction;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::cmp::max;
+
+const USAGE: &str = "
+Usage:
+  breakfast matrix [options] <sv_file> <bam_files>...
+
+Options:
+  --threads=N         Maximum number of threads to use [default: 1]
+  --samples=PATH      Tab-delimited file mapping BAM paths to sample names
+  --reference=PATH    Reference genome FASTA file for reading CRAM files
+  --indexed           Use the BAM index to only scan reads near breakpoints,
+                      and unaligned reads without coordinates
+  --padding=N         Distance around breakpoints to scan with --indexed
+                      [default: 1000]
+  --count-aligned     Also count junction spanning reads that are aligned
+  --count-duplicates  Also count reads marked as duplicates
+  --vaf               Count reads supporting the reference allele, and
+                      estimate variant allele fractions
+  --flank-len=N       Length of the signature flanks on both sides of the
+                      breakpoint [default: 20]
+  --max-mismatches=N  Maximum number of mismatches allowed when matching
+                      signatures against reads [default: 0]
+  --edit-distance     Also allow insertions and deletions when matching
+  --format=FORMAT     Output format (sv or bedpe) [default: sv]
+  --output=PATH       Output file, compressed if it ends in .gz [default: -]
+";
+
+pub fn main() {
+	let args = parse_args(USAGE);
+	let sv_path = args.get_str("<sv_file>");
+	let bam_paths = args.get_vec("<bam_files>");
+	let threads: usize = args.get_str("--threads").parse().unwrap();
+	let samples_path = args.get_str("--samples");
+	let vaf = args.get_bool("--vaf");
+	let max_mismatches: usize = args.get_str("--max-mismatches").parse()
+		.unwrap_or_else(|_| error!("--max-mismatches must be numeric."));
+	let flank_len: usize = args.get_str("--flank-len").parse()
+		.unwrap_or_else(|_| error!("--flank-len must be numeric."));
+	if flank_len < 4 { error!("--flank-len must be at least 4 bp."); }
+	let padding: u32 = args.get_str("--padding").parse()
+		.unwrap_or_else(|_| error!("--padding must be numeric."));
+	let mut settings = Settings {
+		vaf, max_mismatches,
+		edit_distance: args.get_bool("--edit-distance"),
+		count_aligned: args.get_bool("--count-aligned"),
+		count_duplicates: args.get_bool("--count-duplicates"),
+		indexed: args.get_bool("--indexed"),
+		reference: args.get_str("--reference").to_string(), regions: Vec::new(),
+		seed_offsets: seed_offsets(2 * flank_len, max_mismatches)
+			.unwrap_or_else(|err| error!("{}", err))
+	};
+	let format = args.get_str("--format");
+	if format != "sv" && format != "bedpe" {
+		error!("Unknown output format '{}'.", format);
+	}
+
+	// Read the optional sample sheet that maps BAM paths to sample names
+	let mut sample_sheet: HashMap<String, String> = HashMap::new();
+	if !samples_path.is_empty() {
+		let mut file = open_file(samples_path);
+		let mut line = String::new();
+		while read_line(&mut file, &mut line) {
+			if line.starts_with('#') || line.trim().is_empty() { continue; }
+			let cols: Vec<&str> = line.trim_end().split('\t').collect();
+			if cols.len() < 2 {
+				error!("Invalid line in sample sheet {}:\n{}", samples_path, line);
+			}
+			sample_sheet.insert(cols[0].to_string(), cols[1].to_string());
+		}
+	}
+
+	let samples: Vec<String> = bam_paths.iter()
+		.map(|path| sample_name(path, &sample_sheet, &settings.reference)
+			.unwrap_or_else(|err| error!("{}", err)))
+		.collect();
+
+	let mut rearrangements: Vec<Rearrangement> = Vec::new();
+
+	// Read all rearrangement signatures into memory
+	let mut skipped_ambiguous = 0;
+	let mut skipped_short = 0;
+	let mut missing_ref_signatures = 0;
+	let (_, svs) = read_rearrangements(sv_path);
+	for sv in svs {
+		if sv.reads.is_empty() { continue; }
+		let mut signatures: Vec<String> = Vec::new();
+		for read in &sv.reads {
+			let pipe = read.find('|').unwrap();
+			if pipe < flank_len || read.len() < pipe + 1 + flank_len {
+				continue;
+			}
+			signatures.push(format!("{}{}", &read[pipe-flank_len..pipe],
+				&read[pipe+1..pipe+1+flank_len]));
+		}
+		if signatures.is_empty() {
+			skipped_short += 1;
+			continue;
+		}
+
+		let mut signature = most_frequent(&signatures);
+		signature.make_ascii_uppercase();
+		if signature.chars().any(
+			|b| b != 'A' && b != 'C' && b != 'G' && b != 'T') {
+			eprintln!("WARNING: Skipping the following rearrangement because its consensus signature contains ambiguous nucleotides:\n{}", sv);
+			skipped_ambiguous += 1;
+			continue;
+		}
+		let signature_revcomp = reverse_complement(&signature);
+
+		let ref_signatures = [parse_ref_signature(&sv, "REF_SIGNATURE_1", flank_len),
+			parse_ref_signature(&sv, "REF_SIGNATURE_2", flank_len)];
+		if vaf && ref_signatures.iter().all(|s| s.is_none()) {
+			missing_ref_signatures += 1;
+		}
+
+		// Only the breakpoint columns are carried over to the output.
+		let sv = SvRecord {
+			reads: Vec::new(),
+			signature: format!("{}|{}", &signature[..flank_len],
+				&signature[flank_len..]),
+			notes: String::new(), extra: Vec::new(), ..sv
+		};
+
+		rearrangements.push(Rearrangement {
+			signature, signature_revcomp, ref_signatures, sv
+		});
+	}
+	if skipped_ambiguous > 0 {
+		eprintln!("WARNING: Skipped {} rearrangements with signatures containing ambiguous nucleotides.", skipped_ambiguous);
+	}
+	if skipped_short > 0 {
+		eprintln!("WARNING: Skipped {} rearrangements without any supporting reads that extend {} bp from both sides of the breakpoint.", skipped_short, flank_len);
+	}
+	if missing_ref_signatures > 0 {
+		eprintln!("WARNING: Allele fractions cannot be estimated for {} rearrangements without valid reference signatures.", missing_ref_signatures);
+	}
+
+	rearrangements.sort_unstable_by(|a, b| a.signature.cmp(&b.signature));
+	for k in 1..rearrangements.len() {
+		let (a, b) = (&rearrangements[k - 1].sv, &rearrangements[k].sv);
+		if rearrangements[k - 1].signature == rearrangements[k].signature &&
+			(&a.chr, a.strand, a.pos, &a.mchr, a.mstrand, a.mpos) !=
+			(&b.chr, b.strand, b.pos, &b.mchr, b.mstrand, b.mpos) {
+			eprintln!("WARNING: Found two distinct rearrangements with same signature {}:\n{}\n{}\n",
+				rearrangements[k].signature, a, b);
+		}
+	}
+	rearrangements.dedup_by(|a, b| a.signature == b.signature);
+
+	// With --indexed, we only scan the regions around each breakpoint.
+	// Overlapping regions are merged so that no read is counted twice.
+	if settings.indexed {
+		let mut regions: Vec<(String, u32, u32)> = Vec::new();
+		for r in &rearrangements {
+			for (chr, pos) in &[(&r.sv.chr, r.sv.pos), (&r.sv.mchr, r.sv.mpos)] {
+				let pos = *pos as u32;
+				regions.push((chr.to_string(),
+					(pos - 1).saturating_sub(padding), pos + padding));
+			}
+		}
+		regions.sort();
+		for region in regions {
+			if let Some(last) = settings.regions.last_mut() {
+				if last.0 == region.0 && region.1 <= last.2 {
+					last.2 = max(last.2, region.2);
+					continue;
+				}
+			}
+			settings.regions.push(region);
+		}
+	}
+
+	eprintln!("Identifying supporting reads for {} rearrangements in {} BAM files...", rearrangements.len(), bam_paths.len());
+
+	rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()
+		.unwrap();
+	let evidence: Vec<Vec<[u32; 5]>> = bam_paths.par_iter()
+		.map(|bam_path| count_rearrangements(bam_path, &rearrangements, &settings)
+			.unwrap_or_else(|err| error!("{}", err)))
+		.collect();
+
+	// Each sample has a column for reads containing the exact junction
+	// signature. If mismatches are allowed, approximately matching reads
+	// are reported in a separate column. With --count-aligned, the number
+	// of junction spanning reads (exact or approximate) that came from
+	// aligned records is also reported. With --vaf, each sample also has
+	// columns for reference supporting reads at both breakpoints, and the
+	// allele fraction.
+	let approximate = settings.max_mismatches > 0;
+	let count_aligned = settings.count_aligned;
+	let mut header: Vec<String> = Vec::new();
+	for sample in &samples {
+		header.push(sample.clone());
+		if approximate { header.push(format!("{} APPROX", sample)); }
+		if count_aligned { header.push(format!("{} ALIGNED", sample)); }
+		if vaf {
+			header.push(format!("{} REF_1", sample));
+			header.push(format!("{} REF_2", sample));
+			header.push(format!("{} VAF", sample));
+		}
+	}
+
+	let mut output: Vec<SvRecord> = Vec::new();
+	for r in 0..rearrangements.len() {
+		let mut sv = rearrangements[r].sv.clone();
+		for e in &evidence {
+			sv.extra.push(e[r][JUNCTION].to_string());
+			if approximate { sv.extra.push(e[r][APPROX].to_string()); }
+			if count_aligned { sv.extra.push(e[r][ALIGNED].to_string()); }
+			if !vaf { continue; }
+			let ref_signatures = &rearrangements[r].ref_signatures;
+			for (k, ref_signature) in ref_signatures.iter().enumerate() {
+				sv.extra.push(match ref_signature {
+					Some(_) => e[r][REF_1 + k].to_string(), None => "NA".to_string()
+				});
+			}
+			sv.extra.push(allele_fraction(&e[r], ref_signatures));
+		}
+		output.push(sv);
+	}
+	write_output(args.get_str("--output"), format, &header, &output, None);
+}
@@ -0,0 +1,66 @@
+// Command line interface of the "breakfast" executable. Every subcommand
+// has its own module with a docopt usage string and a main() function that
+// calls into the breakfast library. Unlike the library, these functions
+// report errors with a message and exit.
+
+use breakfast::common::{FileReader, FileWriter, SvRecord};
+use breakfast::common::{parse_genome, parse_rearrangements, write_rearrangements};
+use docopt::{Docopt, ArgvMap};
+use std::collections::HashMap;
+
+macro_rules! error {
+	($($arg:tt)+) => ({
+		use std::process::exit;
+		eprint!("ERROR: "); eprintln!($($arg)+); exit(-1);
+	})
+}
+
+pub mod detect; pub mod filter; pub mod annotate;
+pub mod blacklist; pub mod matrix; pub mod convert;
+
+pub fn parse_args(usage: &str) -> ArgvMap {
+	Docopt::new(usage).unwrap().parse().unwrap_or_else(|_| {
+		error!("Invalid arguments.\n{}", usage);
+	})
+}
+
+pub fn open_file(path: &str) -> FileReader {
+	FileReader::open(path).unwrap_or_else(
+		|_| error!("Cannot open file {} for reading.", path))
+}
+
+// Reads the next line of a file. Returns false at the end of the file.
+pub fn read_line(file: &mut FileReader, line: &mut String) -> bool {
+	file.next_line(line).unwrap_or_else(
+		|err| error!("Cannot read from file {}: {}.", file.path(), err))
+}
+
+pub fn read_genome(fasta_path: &str) -> HashMap<String, Vec<u8>> {
+	eprintln!("Reading reference genome into memory...");
+	parse_genome(fasta_path).unwrap_or_else(
+		|_| error!("Genome FASTA file {} could not be read.", fasta_path))
+}
+
+pub fn read_rearrangements(path: &str) -> (Vec<String>, Vec<SvRecord>) {
+	parse_rearrangements(path).unwrap_or_else(|err| error!("{}", err))
+}
+
+pub fn create_output(path: &str) -> FileWriter {
+	FileWriter::create(path).unwrap_or_else(
+		|_| error!("Cannot open file {} for writing.", path))
+}
+
+pub fn close_output(out: FileWriter) {
+	let path = out.path().to_string();
+	out.close().unwrap_or_else(|_| error!("Cannot write to file {}.", path));
+}
+
+// Writes rearrangements into an output file in the given format, which
+// must have been validated by the caller.
+pub fn write_output(path: &str, format: &str, extra_header: &[String],
+	rearrangements: &[SvRecord], genome: Option<&HashMap<String, Vec<u8>>>) {
+	let mut out = create_output(path);
+	write_rearrangements(&mut out, format, extra_header, rearrangements,
+		genome).unwrap_or_else(|_| error!("Cannot write to file {}.", path));
+	close_output(out);
+}
@@ -1,63 +1,692 @@
 
-use docopt::{Docopt, ArgvMap};
-use std::process::{Command, Stdio};
-use std::io::{stdin, BufRead, BufReader};
-use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::fmt;
+use std::collections::HashMap;
 use rust_htslib::bam;
-use rust_htslib::bam::{Read, ReadError};
+use rust_htslib::htslib::{htsFile, bam_hdr_t, hts_idx_t, hts_itr_t, hts_open, hts_close};
+use rust_htslib::htslib::{hts_set_fai_filename, sam_hdr_read, sam_read1};
+use rust_htslib::htslib::{sam_index_load, sam_itr_queryi, hts_itr_next};
+use rust_htslib::htslib::{hts_itr_destroy, hts_idx_destroy, hts_get_format};
+use rust_htslib::htslib::cram as CRAM_FORMAT;
+use rust_htslib::htslib::{BGZF, bgzf_open, bgzf_close};
+use bio::io::fasta;
+use crate::{bedpe, vcf};
 
-macro_rules! error {
-	($($arg:tt)+) => ({
-		use std::process::exit;
-		eprint!("ERROR: "); eprintln!($($arg)+); exit(-1);
-	})
+// Functions of the bundled htslib BGZF library that rust-htslib does not
+// expose. BGZF files are also valid gzip files, and the same functions can
+// read ordinary gzip files and uncompressed files.
+extern "C" {
+	fn bgzf_read(fp: *mut BGZF, data: *mut c_void, length: usize) -> isize;
+	fn bgzf_write(fp: *mut BGZF, data: *const c_void, length: usize) -> isize;
+	fn bgzf_flush(fp: *mut BGZF) -> c_int;
 }
 
-pub fn parse_args(usage: &str) -> ArgvMap {
-	Docopt::new(usage).unwrap().parse().unwrap_or_else(|_| {
-		error!("Invalid arguments.\n{}", usage);
-	})
+// Reader for files that may be gzip or BGZF compressed. Compression is
+// detected from the magic bytes at the start of the file, so that file
+// names do not need to end in ".gz". The path "-" refers to standard input.
+pub struct CompressedReader {
+	bgzf: *mut BGZF
+}
+
+impl CompressedReader {
+	pub fn open(path: &str) -> io::Result<CompressedReader> {
+		let c_path = CString::new(path).map_err(io::Error::other)?;
+		let bgzf = unsafe { bgzf_open(c_path.as_ptr(), b"r\0".as_ptr() as *const c_char) };
+		if bgzf.is_null() { return Err(io::Error::last_os_error()); }
+		Ok(CompressedReader { bgzf })
+	}
+}
+
+impl Read for CompressedReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let len = unsafe {
+			bgzf_read(self.bgzf, buf.as_mut_ptr() as *mut c_void, buf.len()) };
+		if len < 0 {
+			Err(io::Error::new(io::ErrorKind::InvalidData,
+				"corrupted or truncated compressed data"))
+		} else {
+			Ok(len as usize)
+		}
+	}
+}
+
+impl Drop for CompressedReader {
+	fn drop(&mut self) { unsafe { bgzf_close(self.bgzf); } }
 }
 
 pub struct FileReader {
-	bufread: Box<BufRead>
+	path: String,
+	bufread: BufReader<CompressedReader>
 }
 
 impl FileReader {
-	pub fn new(path: &str) -> FileReader {
-		let bufread: Box<BufRead> = if path == "-" {
-			Box::new(BufReader::new(stdin()))
+	pub fn open(path: &str) -> io::Result<FileReader> {
+		Ok(FileReader {
+			path: path.to_string(),
+			bufread: BufReader::new(CompressedReader::open(path)?)
+		})
+	}
+
+	pub fn path(&self) -> &str { &self.path }
+
+	// Reads the next line, including its line terminator. Returns false
+	// at the end of the file.
+	pub fn next_line(&mut self, line: &mut String) -> io::Result<bool> {
+		line.clear();
+		Ok(self.bufread.read_line(line)? > 0)
+	}
+}
+
+// Writer for output files. If the path ends in ".gz", the output is BGZF
+// compressed, and can be decompressed with gunzip or bgzip. The path "-"
+// refers to standard output. Buffered output is only guaranteed to reach
+// the file if close() succeeds.
+pub struct FileWriter {
+	path: String,
+	bgzf: *mut BGZF
+}
+
+impl FileWriter {
+	pub fn create(path: &str) -> io::Result<FileWriter> {
+		let mode: &[u8] = if path.ends_with(".gz") { b"w\0" } else { b"wu\0" };
+		let c_path = CString::new(path).map_err(io::Error::other)?;
+		let bgzf = unsafe { bgzf_open(c_path.as_ptr(), mode.as_ptr() as *const c_char) };
+		if bgzf.is_null() { return Err(io::Error::last_os_error()); }
+		Ok(FileWriter { path: path.to_string(), bgzf })
+	}
+
+	pub fn path(&self) -> &str { &self.path }
+
+	// Flushes any buffered output and closes the file.
+	pub fn close(mut self) -> io::Result<()> {
+		let bgzf = self.bgzf;
+		self.bgzf = ptr::null_mut();
+		if unsafe { bgzf_close(bgzf) } < 0 {
+			Err(io::Error::other(format!("cannot write to file {}", self.path)))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+impl Write for FileWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let len = unsafe {
+			bgzf_write(self.bgzf, buf.as_ptr() as *const c_void, buf.len()) };
+		if len < 0 {
+			Err(io::Error::other(format!("cannot write to file {}", self.path)))
 		} else {
-			let file = File::open(path).unwrap_or_else(
-				|_| error!("Cannot open file {} for reading.", path));
-			if path.ends_with(".gz") {
-				Box::new(BufReader::new(Command::new("gunzip").arg("-c")
-					.stdout(Stdio::piped()).stdin(file).spawn()
-					.unwrap_or_else(|_| error!("Cannot start gunzip process."))
-					.stdout.unwrap()))
+			Ok(len as usize)
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		if unsafe { bgzf_flush(self.bgzf) } < 0 {
+			Err(io::Error::other(format!("cannot write to file {}", self.path)))
+		} else {
+			Ok(())
+		}
+	}
+}
+
+// Writers that are dropped without calling close() are closed here, but
+// any error from writing the remaining output is lost.
+impl Drop for FileWriter {
+	fn drop(&mut self) {
+		if !self.bgzf.is_null() { unsafe { bgzf_close(self.bgzf); } }
+	}
+}
+
+// Special target ID for fetching reads without coordinates from an index
+const HTS_IDX_NOCOOR: i32 = -2;
+
+// Reader for alignments in SAM, BAM or CRAM format. CRAM files are decoded
+// using the given reference genome FASTA file. If the reader was opened
+// with an index, reads can be restricted to a genomic region with fetch().
+pub struct AlignmentReader {
+	path: String,
+	file: *mut htsFile,
+	raw_header: *mut bam_hdr_t,     // Owned by the header view
+	header: bam::HeaderView,
+	index: *mut hts_idx_t,
+	itr: *mut hts_itr_t,
+	is_cram: bool
+}
+
+impl AlignmentReader {
+	pub fn open(path: &str, reference: &str) -> io::Result<AlignmentReader> {
+		let c_path = CString::new(path).map_err(io::Error::other)?;
+		let file = unsafe { hts_open(c_path.as_ptr(), b"r\0".as_ptr() as *const c_char) };
+		if file.is_null() {
+			return Err(io::Error::other(format!("Could not open alignment file {}.", path)));
+		}
+		let fail = |message: String| {
+			unsafe { hts_close(file); }
+			Err(io::Error::other(message))
+		};
+		// Without a reference, htslib would try to download the reference
+		// sequences for CRAM files and then fail on the first record.
+		let is_cram = unsafe { (*hts_get_format(file)).format == CRAM_FORMAT };
+		if is_cram && reference.is_empty() {
+			return fail(format!("CRAM file {} cannot be read without a reference genome (--reference).", path));
+		}
+		if !reference.is_empty() {
+			let c_reference = CString::new(reference).map_err(io::Error::other)?;
+			if unsafe { hts_set_fai_filename(file, c_reference.as_ptr()) } < 0 {
+				return fail(format!("Could not use reference genome {} for reading {}.", reference, path));
+			}
+		}
+		let header = unsafe { sam_hdr_read(file) };
+		if header.is_null() {
+			return fail(format!("Could not read header of alignment file {}.", path));
+		}
+		Ok(AlignmentReader {
+			path: path.to_string(), file, raw_header: header,
+			header: bam::HeaderView::new(header),
+			index: ptr::null_mut(), itr: ptr::null_mut(), is_cram
+		})
+	}
+
+	// Opens an alignment file along with its BAI or CRAI index.
+	pub fn open_indexed(path: &str, reference: &str) -> io::Result<AlignmentReader> {
+		let mut reader = AlignmentReader::open(path, reference)?;
+		let c_path = CString::new(path).map_err(io::Error::other)?;
+		reader.index = unsafe { sam_index_load(reader.file, c_path.as_ptr()) };
+		if reader.index.is_null() {
+			return Err(io::Error::other(
+				format!("Could not read index for alignment file {}.", path)));
+		}
+		Ok(reader)
+	}
+
+	pub fn header(&self) -> &bam::HeaderView { &self.header }
+
+	// Restricts subsequent reads to the given 0-based half-open region.
+	// Returns false if the region could not be fetched, and an error if
+	// the reader was opened without an index.
+	pub fn fetch(&mut self, tid: i32, start: u32, end: u32) -> io::Result<bool> {
+		self.check_indexed()?;
+		if !self.itr.is_null() { unsafe { hts_itr_destroy(self.itr); } }
+		self.itr = unsafe {
+			sam_itr_queryi(self.index, tid, start as i32, end as i32) };
+		Ok(!self.itr.is_null())
+	}
+
+	// Restricts subsequent reads to the unaligned reads that have no
+	// coordinates, stored at the end of a sorted alignment file. The CRAM
+	// decoder skips the unaligned slices unless the query range is widened
+	// to cover their zero start position.
+	pub fn fetch_unplaced(&mut self) -> io::Result<bool> {
+		self.check_indexed()?;
+		if !self.itr.is_null() { unsafe { hts_itr_destroy(self.itr); } }
+		self.itr = unsafe {
+			sam_itr_queryi(self.index, HTS_IDX_NOCOOR, -1, i32::MAX) };
+		Ok(!self.itr.is_null())
+	}
+
+	fn check_indexed(&self) -> io::Result<()> {
+		if self.index.is_null() {
+			return Err(io::Error::other(
+				format!("Alignment file {} is not indexed.", self.path)));
+		}
+		Ok(())
+	}
+
+	fn read(&mut self, record: &mut bam::Record) -> i32 {
+		unsafe {
+			if self.itr.is_null() {
+				sam_read1(self.file, self.raw_header, record.inner)
 			} else {
-				Box::new(BufReader::new(file))
+				// CRAM files have no BGZF stream; the CRAM record reader
+				// ignores the stream argument, as in htslib's sam_itr_next().
+				let bgzf = if self.is_cram { ptr::null_mut() } else { *(*self.file).fp.bgzf() };
+				hts_itr_next(bgzf, self.itr,
+					record.inner as *mut c_void, self.file as *mut c_void)
 			}
-		};
-		FileReader { bufread }
+		}
 	}
+}
 
-	pub fn read_line(&mut self, line: &mut String) -> bool {
-		line.clear();
-		match self.bufread.read_line(line) {
-			Ok(len) => len > 0,
-			_ => { error!("I/O error while reading from file."); }
+impl Drop for AlignmentReader {
+	fn drop(&mut self) {
+		unsafe {
+			if !self.itr.is_null() { hts_itr_destroy(self.itr); }
+			if !self.index.is_null() { hts_idx_destroy(self.index); }
+			hts_close(self.file);
 		}
 	}
 }
 
-// Function for reading BAM records, with proper user-friendly messages.
-// Returns false after reading the last record, or if reading fails.
-pub fn read_bam_record(bam: &mut bam::Reader, record: &mut bam::Record) -> bool {
+// Reads the next alignment record. Returns false after reading the last
+// record, and an error with a user-friendly message if the record is invalid.
+pub fn next_bam_record(bam: &mut AlignmentReader, record: &mut bam::Record)
+	-> io::Result<bool> {
 	match bam.read(record) {
-		Err(ReadError::NoMoreRecord) => false,
-		Err(ReadError::Truncated) => error!("BAM file ended prematurely."),
-		Err(ReadError::Invalid) => error!("Invalid BAM record."),
-		Ok(_) => true
+		-1 => Ok(false),
+		code if code < -1 => Err(io::Error::new(io::ErrorKind::InvalidData,
+			format!("Invalid alignment record in {}.", bam.path))),
+		_ => Ok(true)
+	}
+}
+
+// Reads a genome FASTA file into memory, as a map from chromosome names to
+// nucleotide sequences.
+pub fn parse_genome(fasta_path: &str) -> io::Result<HashMap<String, Vec<u8>>> {
+	let fasta = fasta::Reader::new(CompressedReader::open(fasta_path)?);
+	let mut genome = HashMap::new();
+	for entry in fasta.records() {
+		let chr = entry?;
+		genome.insert(chr.id().to_owned(), chr.seq().to_owned());
+	}
+	Ok(genome)
+}
+
+pub const SV_HEADER: &str = "CHROM\tSTRAND\tPOSITION\tNEARBY FEATURES\tCHROM\tSTRAND\tPOSITION\tNEARBY FEATURES\tSUPPORTING READS\tSIGNATURE\tNOTES";
+
+// A rearrangement in the tab-delimited .sv format. The first breakpoint
+// has the lower coordinate. Strands follow the conventions of
+// "breakfast detect": a '+' strand on the first side (or a '-' strand on
+// the second side) means that the DNA segment joined at the junction lies
+// to the left of the breakpoint position. The NOTES column contains
+// semicolon-separated flags and KEY=VALUE pairs. Any columns beyond NOTES
+// (e.g. sample read counts in matrix output) are kept in `extra`.
+#[derive(Debug, Clone)]
+pub struct SvRecord {
+	pub chr: String,
+	pub strand: bool,
+	pub pos: usize,
+	pub features: String,
+	pub mchr: String,
+	pub mstrand: bool,
+	pub mpos: usize,
+	pub mfeatures: String,
+	pub reads: Vec<String>,
+	pub signature: String,
+	pub notes: String,
+	pub extra: Vec<String>
+}
+
+impl SvRecord {
+	// Parses a line of a .sv file. Returns a description of the problem if
+	// the line is malformed.
+	pub fn from_line(line: &str) -> Result<SvRecord, String> {
+		let cols: Vec<&str> = line.trim_end_matches(&['\n', '\r'][..])
+			.split('\t').collect();
+		if cols.len() < 10 {
+			return Err(format!("Expected at least 10 columns, found {}.", cols.len()));
+		}
+		let sv = SvRecord {
+			chr: parse_chromosome(cols[0])?,
+			strand: parse_strand(cols[1])?,
+			pos: parse_position(cols[2])?,
+			features: cols[3].to_string(),
+			mchr: parse_chromosome(cols[4])?,
+			mstrand: parse_strand(cols[5])?,
+			mpos: parse_position(cols[6])?,
+			mfeatures: cols[7].to_string(),
+			reads: parse_reads(cols[8]),
+			signature: cols[9].to_string(),
+			notes: cols.get(10).unwrap_or(&"").to_string(),
+			extra: cols.iter().skip(11).map(|c| c.to_string()).collect()
+		};
+		sv.validate_sequences()?;
+		Ok(sv)
+	}
+
+	// Checks that every supporting read and the signature consist of
+	// nucleotides, with a single '|' marking the breakpoint.
+	pub fn validate_sequences(&self) -> Result<(), String> {
+		for read in &self.reads {
+			if !is_junction_sequence(read) {
+				return Err(format!("Invalid supporting read '{}'.", read));
+			}
+		}
+		if !self.signature.is_empty() && !is_junction_sequence(&self.signature) {
+			return Err(format!("Invalid signature '{}'.", self.signature));
+		}
+		Ok(())
+	}
+
+	// Classifies the rearrangement based on the chromosomes and strands of
+	// its two breakpoints. Returns DEL (deletion), DUP (tandem duplication),
+	// INV_TT (tail-to-tail inversion), INV_HH (head-to-head inversion) or
+	// TRA (interchromosomal translocation).
+	pub fn sv_type(&self) -> &'static str {
+		if self.chr != self.mchr { return "TRA"; }
+		match (self.strand, self.mstrand) {
+			(true, true) => "DEL",
+			(false, false) => "DUP",
+			(true, false) => "INV_TT",
+			(false, true) => "INV_HH"
+		}
+	}
+
+	// Size of an intrachromosomal event: the number of deleted bases for a
+	// deletion, the length of the duplicated segment for a tandem
+	// duplication, and the length of the inverted segment for an inversion.
+	pub fn size(&self) -> Option<usize> {
+		if self.chr != self.mchr { return None; }
+		let distance = self.mpos.abs_diff(self.pos);
+		Some(match self.sv_type() {
+			"DEL" => distance.saturating_sub(1),
+			"DUP" => distance + 1,
+			_ => distance
+		})
+	}
+
+	// Returns the value of a KEY=VALUE note, or an empty string for a flag.
+	pub fn note(&self, key: &str) -> Option<&str> {
+		self.notes.split(';').find_map(|note| {
+			if note == key { return Some(""); }
+			if note.starts_with(key) && note[key.len()..].starts_with('=') {
+				Some(&note[key.len() + 1..])
+			} else { None }
+		})
+	}
+
+	// Sets the value of a KEY=VALUE note, replacing any previous value.
+	pub fn set_note(&mut self, key: &str, value: &str) {
+		let note = format!("{}={}", key, value);
+		let prefix = format!("{}=", key);
+		let mut found = false;
+		let mut notes: Vec<String> = Vec::new();
+		for n in self.notes.split(';').filter(|n| !n.is_empty()) {
+			if n == key || n.starts_with(&prefix) {
+				if !found { notes.push(note.clone()); }
+				found = true;
+			} else {
+				notes.push(n.to_string());
+			}
+		}
+		if !found { notes.push(note); }
+		self.notes = notes.join(";");
+	}
+
+	// Removes a KEY=VALUE note or flag, if present.
+	pub fn remove_note(&mut self, key: &str) {
+		let prefix = format!("{}=", key);
+		self.notes = self.notes.split(';')
+			.filter(|n| !n.is_empty() && *n != key && !n.starts_with(&prefix))
+			.collect::<Vec<&str>>().join(";");
+	}
+}
+
+impl fmt::Display for SvRecord {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+			self.chr, if self.strand { '+' } else { '-' }, self.pos,
+			self.features,
+			self.mchr, if self.mstrand { '+' } else { '-' }, self.mpos,
+			self.mfeatures, self.reads.join(";"), self.signature, self.notes)?;
+		for col in &self.extra { write!(f, "\t{}", col)?; }
+		Ok(())
+	}
+}
+
+// Nucleotide codes (including IUPAC ambiguity codes) that may appear in
+// junction sequences. Lowercase letters mark mismatches and inserted bases.
+const NUCLEOTIDES: &[u8] = b"ACGTNRYKMSWBDHVacgtnrykmswbdhv";
+
+fn is_junction_sequence(seq: &str) -> bool {
+	seq.bytes().filter(|c| *c == b'|').count() == 1 &&
+		seq.bytes().all(|c| c == b'|' || NUCLEOTIDES.contains(&c))
+}
+
+pub fn parse_chromosome(text: &str) -> Result<String, String> {
+	if text.is_empty() { return Err("Missing chromosome name.".into()); }
+	Ok(text.to_string())
+}
+
+pub fn parse_strand(text: &str) -> Result<bool, String> {
+	match text {
+		"+" => Ok(true), "-" => Ok(false),
+		_ => Err(format!("Invalid strand '{}'.", text))
+	}
+}
+
+// Parses a 1-based chromosomal position.
+pub fn parse_position(text: &str) -> Result<usize, String> {
+	match text.parse::<usize>() {
+		Ok(pos) if pos > 0 => Ok(pos),
+		_ => Err(format!("Invalid position '{}'.", text))
+	}
+}
+
+pub fn parse_reads(text: &str) -> Vec<String> {
+	if text.is_empty() { Vec::new() } else {
+		text.split(';').map(|r| r.to_string()).collect()
+	}
+}
+
+// Error encountered while reading a rearrangement, feature or gene list file
+#[derive(Debug)]
+pub struct ParseError {
+	pub path: String,
+	pub line_num: usize,      // 1-based line number, or 0 if the file could not be read
+	pub message: String
+}
+
+impl ParseError {
+	pub fn new(path: &str, line_num: usize, message: String) -> ParseError {
+		ParseError { path: path.to_string(), line_num, message }
+	}
+
+	// The file could not be opened or read
+	pub fn io(path: &str, err: io::Error) -> ParseError {
+		ParseError::new(path, 0, err.to_string())
+	}
+}
+
+impl fmt::Display for ParseError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if self.line_num == 0 {
+			write!(f, "Cannot read from file {}: {}.", self.path, self.message)
+		} else {
+			write!(f, "Line {} of {}: {}", self.line_num, self.path, self.message)
+		}
+	}
+}
+
+impl std::error::Error for ParseError {}
+
+// Reads all rearrangements from a file in .sv or BEDPE format. The format is
+// recognized from the header line, or from the second column if the file
+// has no header. Returns the names of any columns beyond the standard ones
+// (e.g. sample names in matrix output), and the rearrangements. Otherwise
+// returns an error describing the first malformed line, or the reason why
+// the file could not be read.
+pub fn parse_rearrangements(path: &str)
+	-> Result<(Vec<String>, Vec<SvRecord>), ParseError> {
+	let io_error = |err: io::Error| ParseError::io(path, err);
+	let mut extra_header: Vec<String> = Vec::new();
+	let mut rearrangements: Vec<SvRecord> = Vec::new();
+	let mut file = FileReader::open(path).map_err(io_error)?;
+	let mut line = String::new();
+	let mut line_num = 0;
+	let mut format = "";
+	while file.next_line(&mut line).map_err(io_error)? {
+		line_num += 1;
+		let line = line.trim_end_matches(&['\n', '\r'][..]);
+		if line.is_empty() { continue; }
+		if let Some(header) = header_format(line) {
+			format = header;
+			let standard_cols = if format == "sv" { 11 } else { 15 };
+			extra_header = line.split('\t').skip(standard_cols)
+				.map(|c| c.to_string()).collect();
+		} else if !line.starts_with('#') {
+			let sv = parse_rearrangement(line, line_format(line, format));
+			rearrangements.push(sv.map_err(
+				|message| ParseError::new(path, line_num, message))?);
+		}
+	}
+	Ok((extra_header, rearrangements))
+}
+
+// Recognizes the header line of a .sv or BEDPE file.
+pub fn header_format(line: &str) -> Option<&'static str> {
+	if line.starts_with("CHROM\t") { Some("sv") }
+	else if line.starts_with("#chrom1\t") { Some("bedpe") }
+	else { None }
+}
+
+// Returns the format of a rearrangement line, given the format recognized
+// from the header line (or an empty string if the file has no header). In
+// files without a header, .sv lines are recognized by the strand in their
+// second column.
+pub fn line_format(line: &str, header_format: &str) -> &'static str {
+	let strand = line.split('\t').nth(1).unwrap_or("");
+	if header_format == "sv" || (header_format.is_empty() &&
+		(strand == "+" || strand == "-")) { "sv" } else { "bedpe" }
+}
+
+// Parses a rearrangement line in the given format (sv or bedpe).
+pub fn parse_rearrangement(line: &str, format: &str) -> Result<SvRecord, String> {
+	if format == "sv" { SvRecord::from_line(line) } else { bedpe::from_line(line) }
+}
+
+// Writes rearrangements in the requested output format (sv, bedpe or vcf).
+// Extra columns are not written in VCF output.
+pub fn write_rearrangements(out: &mut impl Write, format: &str,
+	extra_header: &[String], rearrangements: &[SvRecord],
+	genome: Option<&HashMap<String, Vec<u8>>>) -> io::Result<()> {
+	if format == "sv" {
+		write!(out, "{}", SV_HEADER)?;
+		for col in extra_header { write!(out, "\t{}", col)?; }
+		writeln!(out)?;
+		for sv in rearrangements { writeln!(out, "{}", sv)?; }
+		Ok(())
+	} else if format == "bedpe" {
+		bedpe::write_bedpe(out, extra_header, rearrangements)
+	} else if format == "vcf" {
+		vcf::write_vcf(out, rearrangements, genome)
+	} else {
+		Err(io::Error::new(io::ErrorKind::InvalidInput,
+			format!("Unknown output format '{}'.", format)))
+	}
+}
+
+// Fixtures shared by the unit tests of several modules
+#[cfg(test)]
+pub mod test_util {
+	use std::collections::HashMap;
+
+	// Pseudorandom chromosome sequence from a fixed seed
+	pub fn random_chromosome(len: usize, mut seed: u64) -> Vec<u8> {
+		(0..len).map(|_| {
+			seed ^= seed << 13; seed ^= seed >> 7; seed ^= seed << 17;
+			b"ACGT"[(seed % 4) as usize]
+		}).collect()
+	}
+
+	// Genome of two pseudorandom chromosomes, chr1 and chr2
+	pub fn test_genome(chr_len: usize) -> HashMap<String, Vec<u8>> {
+		let mut genome = HashMap::new();
+		genome.insert("chr1".to_string(), random_chromosome(chr_len, 1));
+		genome.insert("chr2".to_string(), random_chromosome(chr_len, 2));
+		genome
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use rust_htslib::htslib::{sam_hdr_write, sam_write1, bam_index_build};
+
+	#[test]
+	fn parse_errors_are_returned() {
+		let err = parse_rearrangements("/nonexistent/tumor.sv").unwrap_err();
+		assert_eq!(err.line_num, 0);
+
+		let path = std::env::temp_dir().join(
+			format!("breakfast_parse_{}.sv", std::process::id()));
+		let path = path.to_str().unwrap();
+		std::fs::write(path, format!("{}\nchr1\t+\tfoo\n", SV_HEADER)).unwrap();
+		let err = parse_rearrangements(path).unwrap_err();
+		std::fs::remove_file(path).unwrap();
+		assert_eq!(err.line_num, 2);
+	}
+
+	// Writes a coordinate sorted and indexed CRAM file with three aligned
+	// reads and one unaligned read, along with its reference genome.
+	// Returns the paths of the CRAM and FASTA files.
+	fn write_test_cram(name: &str) -> (String, String) {
+		let genome = test_util::test_genome(5000);
+		let dir = std::env::temp_dir().join(
+			format!("breakfast_{}_{}", name, std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+		let path = |file: &str| dir.join(file).to_str().unwrap().to_string();
+
+		let mut fasta = String::new();
+		for chr in &["chr1", "chr2"] {
+			fasta += &format!(">{}\n{}\n", chr, std::str::from_utf8(&genome[*chr]).unwrap());
+		}
+		std::fs::write(path("genome.fa"), fasta).unwrap();
+
+		let mut sam = "@HD\tVN:1.6\tSO:coordinate\n@SQ\tSN:chr1\tLN:5000\n@SQ\tSN:chr2\tLN:5000\n".to_string();
+		for (qname, chr, pos) in &[("r1", "chr1", 100), ("r2", "chr2", 1000), ("r3", "chr2", 3000)] {
+			let seq = std::str::from_utf8(&genome[*chr][*pos..pos + 50]).unwrap();
+			sam += &format!("{}\t0\t{}\t{}\t60\t50M\t*\t0\t0\t{}\t*\n", qname, chr, pos + 1, seq);
+		}
+		sam += &format!("u1\t4\t*\t0\t0\t*\t*\t0\t0\t{}\t*\n",
+			std::str::from_utf8(&genome["chr1"][2000..2050]).unwrap());
+		std::fs::write(path("reads.sam"), sam).unwrap();
+
+		let mut sam = AlignmentReader::open(&path("reads.sam"), "").unwrap();
+		let c_cram = CString::new(path("reads.cram")).unwrap();
+		let c_fasta = CString::new(path("genome.fa")).unwrap();
+		let mut record = bam::Record::new();
+		unsafe {
+			let cram = hts_open(c_cram.as_ptr(), b"wc\0".as_ptr() as *const c_char);
+			assert_eq!(hts_set_fai_filename(cram, c_fasta.as_ptr()), 0);
+			assert_eq!(sam_hdr_write(cram, sam.raw_header), 0);
+			while next_bam_record(&mut sam, &mut record).unwrap() {
+				assert!(sam_write1(cram, sam.raw_header, record.inner) >= 0);
+			}
+			assert_eq!(hts_close(cram), 0);
+			assert_eq!(bam_index_build(c_cram.as_ptr(), 0), 0);
+		}
+		(path("reads.cram"), path("genome.fa"))
+	}
+
+	fn read_names(reader: &mut AlignmentReader) -> Vec<String> {
+		let mut record = bam::Record::new();
+		let mut names = Vec::new();
+		while next_bam_record(reader, &mut record).unwrap() {
+			names.push(String::from_utf8(record.qname().to_vec()).unwrap());
+		}
+		names
+	}
+
+	#[test]
+	fn fetch_from_indexed_cram() {
+		let (cram, fasta) = write_test_cram("fetch");
+		let mut reader = AlignmentReader::open_indexed(&cram, &fasta).unwrap();
+		assert!(reader.fetch(1, 900, 1200).unwrap());
+		assert_eq!(read_names(&mut reader), vec!["r2"]);
+		assert!(reader.fetch(0, 0, 5000).unwrap());
+		assert_eq!(read_names(&mut reader), vec!["r1"]);
+		assert!(reader.fetch_unplaced().unwrap());
+		assert_eq!(read_names(&mut reader), vec!["u1"]);
+
+		// Regions cannot be fetched without an index
+		let mut reader = AlignmentReader::open(&cram, &fasta).unwrap();
+		assert!(reader.fetch(1, 900, 1200).is_err());
+		assert!(reader.fetch_unplaced().is_err());
+		drop(reader);
+		std::fs::remove_dir_all(std::path::Path::new(&cram).parent().unwrap()).unwrap();
+	}
+
+	#[test]
+	fn cram_requires_reference() {
+		let (cram, fasta) = write_test_cram("reference");
+		assert!(AlignmentReader::open(&cram, "").is_err());
+		let err = AlignmentReader::open(&cram, "/nonexistent/genome.fa").err().unwrap();
+		assert!(err.to_string().starts_with("Could not use reference genome"));
+		assert!(AlignmentReader::open(&cram, &fasta).is_ok());
+		std::fs::remove_dir_all(std::path::Path::new(&cram).parent().unwrap()).unwrap();
 	}
 }
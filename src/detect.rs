@@ -1,215 +1,581 @@
 
-use crate::common::parse_args;
+use crate::common::{SvRecord, AlignmentReader, next_bam_record};
+use crate::align::{Alignment, FMIndex};
 use std::mem::swap;
-use std::{str, thread};
+use std::{io, str, thread};
 use std::process::{Command, Stdio};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::cmp::{min, max, Ordering};
 use std::io::{BufReader, BufWriter, BufRead, Write};
 use rust_htslib::bam;
-use rust_htslib::bam::Read;
-use bio::io::fasta;
+use rust_htslib::bam::record::Cigar;
 use bio::alphabets::dna;
 
+// Breakpoint evidence from a single read whose 5' and 3' anchors aligned
+// to different genomic locations.
 #[derive(Debug)]
-struct Evidence {
-	chr: String,
-	pos: usize,               // Leftmost position of anchor #1 alignment
-	strand: bool,
-	mchr: String,
-	mpos: usize,              // Leftmost position of anchor #2 alignment
-	mstrand: bool,
-	sequence: Vec<u8>,        // Full sequence of breakpoint overlapping read
-	signature: Vec<u8>,       // Breakpoint signature (8 bp from both flanks)
-	//ref_signature_1: Vec<u8>, // Normal sequence around the 1st breakpoint
-	//ref_signature_2: Vec<u8>, // Normal sequence around the 2nd breakpoint
-	frag_id: Vec<u8>          // Fragment QNAME from BAM file
+pub struct Evidence {
+	pub chr: String,
+	pub pos: usize,               // Leftmost position of anchor #1 alignment
+	pub strand: bool,
+	pub mchr: String,
+	pub mpos: usize,              // Leftmost position of anchor #2 alignment
+	pub mstrand: bool,
+	pub sequence: Vec<u8>,        // Full sequence of breakpoint overlapping read
+	pub signature: Vec<u8>,       // Breakpoint signature (8 bp from both flanks)
+	pub anchor_mismatches: usize, // Total mismatches in both anchor alignments
+	pub homology: Vec<u8>,        // Microhomology shared by both flanks
+	pub insertion: Vec<u8>,       // Non-templated bases between the flanks
+	pub ref_signature_1: Vec<u8>, // Normal sequence around the 1st breakpoint
+	pub ref_signature_2: Vec<u8>, // (empty if too close to a chromosome end)
+	pub read_num: usize,          // Read number, for resolving ambiguous reads
+	pub ambiguous: bool,          // Read had multiple candidate placements
+	pub frag_id: Vec<u8>          // Fragment QNAME from BAM file
 }
 
-const USAGE: &str = "
-Usage:
-  breakfast detect [options] <bam_file> <genome>
-
-Options:
-  --anchor-len=N       Anchor length for split read analysis [default: 30]
-  --anchor-mm=N        Mismatches allowed in anchor alignments [default: 0]
-  --max-frag-len=N     Maximum fragment length [default: 5000]
-  --min-evidence=N     Minimum number of supporting DNA fragments [default: 2]
-  --count-duplicates   Count also reads that have been flagged as duplicates
-";
-
-pub fn main() {
-	let args = parse_args(USAGE);
-	let sam_path = args.get_str("<bam_file>").to_string();
-	let genome_path = args.get_str("<genome>");
-	let anchor_len: usize = args.get_str("--anchor-len").parse().unwrap();
-	//let anchor_mm: usize = args.get_str("--anchor-mm").parse().unwrap();
-	let max_frag_len: usize = args.get_str("--max-frag-len").parse().unwrap();
-	let min_evidence: usize = args.get_str("--min-evidence").parse().unwrap();
-	let count_duplicates = args.get_bool("--count-duplicates");
-
-	let fasta = fasta::Reader::from_file(format!("{}.fa", genome_path))
-		.unwrap_or_else(|_| error!("Genome FASTA file {}.fa could not be read.", genome_path));
-	eprintln!("Reading reference genome into memory...");
-
-	let mut genome = HashMap::new();
-	for entry in fasta.records() {
-		let chr = entry.unwrap();
-		genome.insert(chr.id().to_owned(), chr.seq().to_owned());
-	}
-
-	// TODO: Handle reads with multiple alignments...
-	eprintln!("Splitting unaligned reads into {} bp anchors and aligning against the genome...", anchor_len);
-	let bowtie = Command::new("bowtie")
-		.args(&["-f", "-p1", "-v0", "-m1", "-B1", "--suppress", "5,6,7,8", &genome_path, "-"])
-		.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap_or_else(
-		|_| error!("Could not start Bowtie process."));
-
-	let mut bowtie_in = BufWriter::new(bowtie.stdin.unwrap());
-	let bowtie_out = BufReader::new(bowtie.stdout.unwrap());
-
-	thread::spawn(move || {
-		dispatch_reads_to_bowtie(&sam_path, &mut bowtie_in, anchor_len, count_duplicates);
-	});
+// Unaligned read whose anchors have been aligned against the genome
+struct AnchoredRead<'a> {
+	num: usize,
+	frag_id: &'a [u8],
+	seq: &'a [u8],
+	mate: Option<(&'a str, usize)>   // Position of the read's mate, if aligned
+}
 
-	let mut evidence: Vec<Evidence> = Vec::new();
-	let mut prev = String::new();
-	let mut prev_read_num = 0;
+// Soft clipped read whose clipped end was aligned by the upstream aligner,
+// as listed in the SA tag of its primary alignment record. The anchors are
+// placed based on these alignments instead of being realigned.
+struct SplitAlignedRead {
+	num: usize,
+	frag_id: Vec<u8>,
+	seq: Vec<u8>,
+	mate: Option<(String, usize)>,
+	anchor_5p: Alignment,
+	anchor_3p: Alignment
+}
 
-	for l in bowtie_out.lines() {
-		let line = l.unwrap();
+// Read pair whose mates align to different chromosomes, or with an
+// abnormal orientation or insert size. The pair is oriented so that the
+// first mate has the lower coordinate, and strands follow the same
+// convention as in Evidence.
+pub struct DiscordantPair {
+	pub chr: String,
+	pub pos: usize,               // Leftmost position of mate #1 alignment
+	pub strand: bool,
+	pub mchr: String,
+	pub mpos: usize,              // Leftmost position of mate #2 alignment
+	pub mstrand: bool,
+	pub frag_id: Vec<u8>          // Fragment QNAME from BAM file
+}
 
-		let read_num: usize = line.split(':').nth(1).unwrap().parse().unwrap();
+#[derive(Clone)]
+pub struct Settings {
+	pub anchor_len: usize,
+	pub anchor_mm: usize,
+	pub max_placements: usize,
+	pub max_frag_len: usize,
+	pub min_evidence: usize,
+	pub count_duplicates: bool,
+	pub soft_clipped: bool,
+	pub discordant_pairs: bool,
+	pub reference: String         // Reference genome for CRAM files
+}
 
-		if line.starts_with("5p:") {
-			prev = line;
-			prev_read_num = read_num;
-			continue;
-		} else if line.starts_with("3p:") && read_num != prev_read_num {
-			continue;
-		}
+// Length of the flanks in the reference signatures around each breakpoint.
+// This is an upper limit for the signature flank length used by matrix.
+const REF_FLANK_LEN: usize = 30;
 
-		let mut anchor_info = line.split(':');
-		let frag_id = anchor_info.nth(2).unwrap().as_bytes();
-		let mut seq: Vec<u8> = anchor_info.next().unwrap().as_bytes().to_vec();
-		let full_len: usize = seq.len();
-
-		let mut cols = prev.split('\t');
-		let mut mcols = line.split('\t');
-		cols.next(); mcols.next();   // Skip first column
-
-		let mut strand = cols.next().unwrap() == "+";
-		let mut mstrand = mcols.next().unwrap() == "+";
-		let mut chr = cols.next().unwrap();
-		let mut mchr = mcols.next().unwrap();
-		let mut pos: usize = cols.next().unwrap().parse().unwrap();
-		let mut mpos: usize = mcols.next().unwrap().parse().unwrap();
-
-		// Reorient the read so that anchor #1 has the lower coordinate.
-		// This simplifies downstream analysis where we cluster the
-		// rearrangement evidence by position.
-		if chr > mchr || (chr == mchr && pos > mpos) {
-			swap(&mut chr, &mut mchr);
-			swap(&mut pos, &mut mpos);
-			let tmp = strand; strand = !mstrand; mstrand = !tmp;
-			seq = dna::revcomp(&seq);
-		}
+// Identifies rearrangements based on clusters of split read evidence, and
+// describes each of them as an .sv record. A rearrangement must be supported
+// by at least settings.min_evidence DNA fragments, counting both split reads
+// and any discordant read pairs collected for it.
+pub fn call_rearrangements(mut evidence: Vec<Evidence>,
+	mut discordant: Vec<DiscordantPair>, settings: &Settings) -> Vec<SvRecord> {
 
-		// If the read is at the very edge of a chromosome, ignore it.
-		if pos + full_len >= genome[chr].len() { continue; }
-		if mpos + full_len >= genome[mchr].len() { continue; }
+	eprintln!("Sorting rearrangement supporting reads by position...");
+	evidence.sort_by(|a,b|
+		if a.chr < b.chr { Ordering::Less }
+		else if a.chr > b.chr { Ordering::Greater }
+		else if a.pos < b.pos { Ordering::Less }
+		else if a.pos > b.pos { Ordering::Greater }
+		else { Ordering::Equal });
+	discordant.sort_by(|a, b| (&a.chr, a.pos).cmp(&(&b.chr, b.pos)));
 
-		let left_grch = if strand == true {
-			genome[chr][pos-1..pos+full_len-1].to_vec()
-		} else {
-			dna::revcomp(&genome[chr][pos+anchor_len-full_len-1..pos+anchor_len-1].to_vec())
-		};
+	if settings.max_placements > 1 {
+		eprintln!("Resolving reads with multiple candidate placements...");
+		evidence = resolve_ambiguous(evidence, settings.max_frag_len);
+	}
 
-		let right_grch = if mstrand == true {
-			genome[mchr][mpos+anchor_len-full_len-1..mpos+anchor_len-1].to_vec()
-		} else {
-			dna::revcomp(&genome[mchr][mpos-1..mpos+full_len-1].to_vec())
+	eprintln!("Identifying rearrangements based on clusters of discordant reads...");
+	let mut rearrangements: Vec<SvRecord> = Vec::new();
+	for indices in cluster_evidence(&evidence, settings.max_frag_len) {
+		let read = &evidence[indices[0]];
+		let mut cluster: Vec<&Evidence> =
+			indices.iter().map(|e| &evidence[*e]).collect();
+
+		// Discordant read pairs count towards the minimum evidence, so that
+		// junctions with low split read coverage can still be reported.
+		let num_discordant = count_discordant_pairs(&discordant, read,
+			&cluster, settings.max_frag_len);
+		if cluster.len() + num_discordant < settings.min_evidence { continue; }
+		cluster = remove_duplicates(cluster);
+		if cluster.len() + num_discordant < settings.min_evidence { continue; }
+
+		let mut sv = SvRecord {
+			chr: read.chr.clone(), strand: read.strand, pos: read.pos,
+			features: String::new(),
+			mchr: read.mchr.clone(), mstrand: read.mstrand, mpos: read.mpos,
+			mfeatures: String::new(),
+			reads: cluster.iter().map(|r|
+				String::from_utf8(r.sequence.clone()).unwrap()).collect(),
+			signature: String::from_utf8(read.signature.clone()).unwrap(),
+			notes: String::new(), extra: Vec::new()
 		};
 
-		// Identify the breakpoint location that minimizes the number of
-		// nucleotide mismatches between the read and the breakpoint flanks.
-		let mut mismatches: Vec<usize> = vec![0; full_len];
-		for k in 0..anchor_len {
-			mismatches[anchor_len] += (seq[k] != left_grch[k]) as usize;
+		let mut notes: Vec<String> = Vec::new();
+		notes.push(format!("TYPE={}", sv.sv_type()));
+		if let Some(size) = sv.size() {
+			notes.push(format!("SIZE={}", size));
 		}
-		for k in anchor_len..full_len {
-			mismatches[anchor_len] += (seq[k] != right_grch[k]) as usize;
+		if !read.homology.is_empty() {
+			notes.push(format!("HOMLEN={};HOMSEQ={}", read.homology.len(),
+				str::from_utf8(&read.homology).unwrap()));
 		}
-		for k in anchor_len+1..full_len-anchor_len {
-			mismatches[k] = mismatches[k-1] +
-				(seq[k-1] != left_grch[k-1]) as usize -
-				(seq[k-1] != right_grch[k-1]) as usize;
+		if !read.insertion.is_empty() {
+			notes.push(format!("INSLEN={};INSSEQ={}", read.insertion.len(),
+				str::from_utf8(&read.insertion).unwrap()));
 		}
-		let mut bp = anchor_len;
-		let mut least_mismatches = mismatches[anchor_len];
-		for k in anchor_len+1..full_len-anchor_len {
-			if mismatches[k] < least_mismatches {
-				bp = k; least_mismatches = mismatches[k];
-			}
+		if !read.ref_signature_1.is_empty() {
+			notes.push(format!("REF_SIGNATURE_1={}",
+				str::from_utf8(&read.ref_signature_1).unwrap()));
+		}
+		if !read.ref_signature_2.is_empty() {
+			notes.push(format!("REF_SIGNATURE_2={}",
+				str::from_utf8(&read.ref_signature_2).unwrap()));
+		}
+		if cluster.iter().any(|r| r.ambiguous) {
+			notes.push("AMBIGUOUS".to_string());
 		}
+		if cluster.iter().any(|r| r.anchor_mismatches > 0) {
+			let mm: Vec<String> = cluster.iter()
+				.map(|r| r.anchor_mismatches.to_string()).collect();
+			notes.push(format!("ANCHOR_MM={}", mm.join(",")));
+		}
+		if settings.discordant_pairs {
+			notes.push(format!("DISCORDANT_PAIRS={}", num_discordant));
+		}
+
+		sv.notes = notes.join(";");
+		rearrangements.push(sv);
+	}
+	rearrangements
+}
+
+// Constructs the normal reference sequence around a breakpoint that lies
+// between bases #before and #before + 1 (1-based) of the chromosome, with
+// REF_FLANK_LEN bp from both sides, in forward genome orientation. Returns
+// None if the breakpoint is too close to the edge of the chromosome.
+fn reference_signature(chr_seq: &[u8], before: usize) -> Option<Vec<u8>> {
+	if before < REF_FLANK_LEN || before + REF_FLANK_LEN > chr_seq.len() {
+		return None;
+	}
+	let mut signature = chr_seq[before-REF_FLANK_LEN..before].to_ascii_uppercase();
+	signature.push(b'|');
+	signature.extend(chr_seq[before..before+REF_FLANK_LEN].to_ascii_uppercase());
+	Some(signature)
+}
 
-		let mut junction: Vec<u8> = vec![' ' as u8; full_len + 1];
-		for k in 0..bp {
-			junction[k] = if seq[k] == left_grch[k] { seq[k] } else { seq[k].to_ascii_lowercase() };
+// Constructs a breakpoint evidence record for a read whose 5' and 3' anchors
+// aligned to the given genomic locations. Returns None if the read lies at
+// the very edge of a chromosome.
+pub fn junction_evidence(genome: &HashMap<String, Vec<u8>>, anchor_len: usize,
+	frag_id: &[u8], read_seq: &[u8], anchor_5p: &Alignment,
+	anchor_3p: &Alignment) -> Option<Evidence> {
+
+	let mut seq = read_seq.to_vec();
+	let full_len: usize = seq.len();
+
+	let mut strand = anchor_5p.strand;
+	let mut mstrand = anchor_3p.strand;
+	let mut chr = anchor_5p.chr.as_str();
+	let mut mchr = anchor_3p.chr.as_str();
+	let mut pos = anchor_5p.pos;
+	let mut mpos = anchor_3p.pos;
+
+	// Reorient the read so that anchor #1 has the lower coordinate.
+	// This simplifies downstream analysis where we cluster the
+	// rearrangement evidence by position.
+	if chr > mchr || (chr == mchr && pos > mpos) {
+		swap(&mut chr, &mut mchr);
+		swap(&mut pos, &mut mpos);
+		let tmp = strand; strand = !mstrand; mstrand = !tmp;
+		seq = dna::revcomp(&seq);
+	}
+
+	// If the read is at the very edge of a chromosome, ignore it.
+	if pos + full_len >= genome[chr].len() { return None; }
+	if mpos + full_len >= genome[mchr].len() { return None; }
+
+	let left_grch = if strand {
+		genome[chr][pos-1..pos+full_len-1].to_vec()
+	} else {
+		dna::revcomp(&genome[chr][pos+anchor_len-full_len-1..pos+anchor_len-1].to_vec())
+	};
+
+	let right_grch = if mstrand {
+		genome[mchr][mpos+anchor_len-full_len-1..mpos+anchor_len-1].to_vec()
+	} else {
+		dna::revcomp(&genome[mchr][mpos-1..mpos+full_len-1].to_vec())
+	};
+
+	// Count the nucleotide mismatches between the read and the breakpoint
+	// flanks. left_mm[k] is the number of mismatches in read[..k] against
+	// the left flank, and right_mm[k] in read[k..] against the right flank.
+	let mut left_mm: Vec<usize> = vec![0; full_len + 1];
+	let mut right_mm: Vec<usize> = vec![0; full_len + 1];
+	for k in 0..full_len {
+		left_mm[k+1] = left_mm[k] + (seq[k] != left_grch[k]) as usize;
+		let j = full_len - 1 - k;
+		right_mm[j] = right_mm[j+1] + (seq[j] != right_grch[j]) as usize;
+	}
+
+	// Identify the breakpoint location that minimizes the number of
+	// nucleotide mismatches between the read and the breakpoint flanks.
+	// If the flanks share microhomologous sequence, several locations are
+	// equally good, and we pick the leftmost one.
+	let mut bp = anchor_len;
+	for k in anchor_len+1..full_len-anchor_len {
+		if left_mm[k] + right_mm[k] < left_mm[bp] + right_mm[bp] { bp = k; }
+	}
+
+	// Check if the junction is better explained by non-templated bases
+	// inserted between the flanks, so that the left flank ends at bp and
+	// the right flank starts at mbp. In units of a quarter mismatch, each
+	// inserted base costs 1 and opening the insertion costs 3. A single
+	// mismatch at the junction therefore ties with a 1 bp insertion and is
+	// kept as a mismatch, but a run of two or more mismatching bases is
+	// reported as an insertion, even if some of its bases happen to match
+	// the flanks.
+	let mut mbp = bp;
+	let mut least_cost = 4 * (left_mm[bp] + right_mm[bp]);
+	let last = full_len - anchor_len;
+	for (a, mm_a) in left_mm.iter().enumerate().take(last).skip(anchor_len) {
+		for (b, mm_b) in right_mm.iter().enumerate().take(last).skip(a + 1) {
+			let cost = 4 * (mm_a + mm_b) + (b - a) + 3;
+			if cost < least_cost { bp = a; mbp = b; least_cost = cost; }
 		}
-		junction[bp] = '|' as u8;
+	}
+	let insertion = seq[bp..mbp].to_vec();
+
+	// Microhomology is the sequence immediately after the breakpoint that
+	// matches both flanks.
+	let mut homology: Vec<u8> = Vec::new();
+	if insertion.is_empty() {
 		for k in bp..full_len {
-			junction[k+1] = if seq[k] == right_grch[k] { seq[k] } else { seq[k].to_ascii_lowercase() };
+			if seq[k] != left_grch[k] || seq[k] != right_grch[k] { break; }
+			homology.push(seq[k]);
+		}
+	}
+
+	let mut junction: Vec<u8> = vec![b' '; full_len + 1];
+	for k in 0..bp {
+		junction[k] = if seq[k] == left_grch[k] { seq[k] } else { seq[k].to_ascii_lowercase() };
+	}
+	junction[bp] = b'|';
+	for k in bp..mbp { junction[k+1] = seq[k].to_ascii_lowercase(); }
+	for k in mbp..full_len {
+		junction[k+1] = if seq[k] == right_grch[k] { seq[k] } else { seq[k].to_ascii_lowercase() };
+	}
+
+	// Construct a breakpoint signature, composed of 8 bp from both flanks
+	let mut signature = vec![b' '; 17];
+	signature[..8].copy_from_slice(&left_grch[bp - 8..bp]);
+	signature[8] = b'|';
+	signature[9..].copy_from_slice(&right_grch[mbp..mbp + 8]);
+
+	// Calculate the position of the first nucleotide immediately before
+	// the breakpoint, on both sides of the junction.
+	let left_bp_pos = if strand {
+		pos + bp - 1
+	} else {
+		pos + anchor_len - bp
+	};
+	let right_bp_pos = if mstrand {
+		mpos + anchor_len - (full_len - mbp)
+	} else {
+		mpos + (full_len - mbp) - 1
+	};
+
+	// Construct reference signatures for both breakpoints. The DNA segment
+	// lies to the left of the first breakpoint if its strand is +, and to
+	// the left of the second breakpoint if its strand is -. Breakpoints near
+	// the ends of a chromosome have no reference signature.
+	let ref_signature_1 = reference_signature(&genome[chr],
+		if strand { left_bp_pos } else { left_bp_pos - 1 }).unwrap_or_default();
+	let ref_signature_2 = reference_signature(&genome[mchr],
+		if mstrand { right_bp_pos - 1 } else { right_bp_pos }).unwrap_or_default();
+
+	Some(Evidence {
+		chr: chr.to_string(), pos: left_bp_pos, strand,
+		mchr: mchr.to_string(), mpos: right_bp_pos, mstrand,
+		sequence: junction, signature,
+		anchor_mismatches: anchor_5p.mismatches + anchor_3p.mismatches,
+		homology, insertion, ref_signature_1, ref_signature_2, read_num: 0, ambiguous: false,
+		frag_id: frag_id.to_vec() })
+}
+
+// Constructs breakpoint evidence for every combination of the candidate
+// placements of a read's 5' and 3' anchors. Reads that can be explained
+// without a rearrangement are discarded. If the anchors have multiple
+// placements, combinations that are consistent with the position of the
+// read's mate are preferred. The evidence is flagged as ambiguous if more
+// than one combination remains.
+fn read_evidence(genome: &HashMap<String, Vec<u8>>, settings: &Settings,
+	read: &AnchoredRead, anchors_5p: &[Alignment], anchors_3p: &[Alignment])
+	-> Vec<Evidence> {
+
+	let mut candidates: Vec<Evidence> = Vec::new();
+	for anchor_5p in anchors_5p {
+		for anchor_3p in anchors_3p {
+			if let Some(e) = junction_evidence(genome, settings.anchor_len,
+				read.frag_id, read.seq, anchor_5p, anchor_3p) {
+				candidates.push(e);
+			}
 		}
+	}
 
-		// Construct a breakpoint signature, composed of 8 bp from both flanks
-		let mut signature = vec![' ' as u8; 17];
-		for k in 0..8 { signature[k] = left_grch[bp - 8 + k]; }
-		signature[8] = '|' as u8;
-		for k in 0..8 { signature[9 + k] = right_grch[bp + k]; }
+	if candidates.iter().any(|e| e.chr == e.mchr && e.strand && e.mstrand
+		&& e.mpos == e.pos + 1) {
+		return Vec::new();
+	}
+	for e in &mut candidates { e.read_num = read.num; }
+	if candidates.len() <= 1 { return candidates; }
 
-		// Construct a reference signature for the first breakpoint.
-		// TODO: Implement
+	if let Some((mate_chr, mate_pos)) = read.mate {
+		let near_mate = |chr: &str, pos: usize| chr == mate_chr &&
+			(pos as i64 - mate_pos as i64).abs() <= settings.max_frag_len as i64;
+		if candidates.iter().any(|e| near_mate(&e.chr, e.pos) || near_mate(&e.mchr, e.mpos)) {
+			candidates.retain(|e| near_mate(&e.chr, e.pos) || near_mate(&e.mchr, e.mpos));
+		}
+	}
+	if candidates.len() > 1 {
+		for e in &mut candidates { e.ambiguous = true; }
+	}
+	candidates
+}
 
-		// Calculate the position of the first nucleotide immediately before
-		// the breakpoint, on both sides of the junction.
-		let left_bp_pos = if strand == true {
-			pos + bp - 1
-		} else {
-			pos + anchor_len - bp
-		};
-		let right_bp_pos = if mstrand == true {
-			mpos + anchor_len - (full_len - bp)
-		} else {
-			mpos + (full_len - bp)
-		};
+// Resolves reads with multiple candidate placements by keeping only the
+// placements whose clusters have the most support from other reads. If
+// multiple placements are equally well supported, all of them are kept.
+// The evidence must be sorted by position.
+pub fn resolve_ambiguous(evidence: Vec<Evidence>, max_frag_len: usize)
+	-> Vec<Evidence> {
 
-		evidence.push(Evidence {
-			chr: chr.to_string(), pos: left_bp_pos, strand: strand,
-			mchr: mchr.to_string(), mpos: right_bp_pos, mstrand: mstrand,
-			sequence: junction, signature: signature,
-			frag_id: frag_id.to_vec() });
+	let mut support = vec![0; evidence.len()];
+	for cluster in cluster_evidence(&evidence, max_frag_len) {
+		let reads: HashSet<usize> = cluster.iter()
+			.map(|e| evidence[*e].read_num).collect();
+		for e in cluster { support[e] = reads.len(); }
 	}
 
-	eprintln!("Found {} rearrangement supporting reads.", evidence.len());
+	let mut best_support: HashMap<usize, usize> = HashMap::new();
+	for (e, read) in evidence.iter().enumerate() {
+		if !read.ambiguous { continue; }
+		let best = best_support.entry(read.read_num).or_insert(0);
+		*best = max(*best, support[e]);
+	}
 
-	eprintln!("Sorting rearrangement supporting reads by position...");
-	evidence.sort_by(|a,b|
-		if a.chr < b.chr { Ordering::Less }
-		else if a.chr > b.chr { Ordering::Greater }
-		else if a.pos < b.pos { Ordering::Less }
-		else if a.pos > b.pos { Ordering::Greater }
-		else { Ordering::Equal });
+	evidence.into_iter().enumerate()
+		.filter(|(e, read)| !read.ambiguous ||
+			support[*e] == best_support[&read.read_num])
+		.map(|(_, read)| read).collect()
+}
 
-	eprintln!("Identifying rearrangements based on clusters of discordant reads...");
-	println!("CHROM\tSTRAND\tPOSITION\tNEARBY FEATURES\tCHROM\tSTRAND\tPOSITION\tNEARBY FEATURES\tSUPPORTING READS\tSIGNATURE\tNOTES");
+// Collects breakpoint evidence from the candidate reads of an alignment file,
+// by aligning their anchors with Bowtie against the Bowtie index at
+// genome_path. Discordant read pairs are also returned if requested.
+pub fn align_anchors_with_bowtie(genome: &HashMap<String, Vec<u8>>,
+	settings: &Settings, sam_path: &str, genome_path: &str)
+	-> io::Result<(Vec<Evidence>, Vec<DiscordantPair>)> {
+
+	let mismatch_arg = format!("-v{}", settings.anchor_mm);
+	let placements_arg = format!("{}", settings.max_placements);
+	let mut bowtie = Command::new("bowtie")
+		.args(["-f", "-p1", &mismatch_arg, "-k", &placements_arg, "-m", &placements_arg, "-B1", "--suppress", "5,6,7", genome_path, "-"])
+		.stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().map_err(
+		|err| io::Error::new(err.kind(), format!("Could not start Bowtie process: {}.", err)))?;
+
+	let mut bowtie_in = BufWriter::new(bowtie.stdin.take().unwrap());
+	let bowtie_out = BufReader::new(bowtie.stdout.take().unwrap());
+
+	let sam_path = sam_path.to_string();
+	let dispatch_settings = settings.clone();
+	let dispatcher = thread::spawn(move || -> io::Result<_> {
+		let reads = dispatch_reads_to_bowtie(&sam_path, &mut bowtie_in,
+			&dispatch_settings)?;
+		bowtie_in.flush()?;
+		Ok(reads)
+	});
+
+	// Bowtie reports all alignments of a read on consecutive lines, so we
+	// gather the alignments of both anchors before constructing evidence.
+	let mut evidence: Vec<Evidence> = Vec::new();
+	let mut alignments: Vec<String> = Vec::new();
+	for l in bowtie_out.lines() {
+		let line = l?;
+		if !alignments.is_empty() &&
+			bowtie_read_num(&line) != bowtie_read_num(&alignments[0]) {
+			evidence.extend(bowtie_read_evidence(genome, settings, &alignments));
+			alignments.clear();
+		}
+		alignments.push(line);
+	}
+	if !alignments.is_empty() {
+		evidence.extend(bowtie_read_evidence(genome, settings, &alignments));
+	}
+
+	let status = bowtie.wait().map_err(|err| io::Error::new(err.kind(),
+		format!("Could not wait for Bowtie process to finish: {}.", err)))?;
+	if !status.success() {
+		return Err(io::Error::other("Bowtie process exited with an error."));
+	}
+	let (discordant, split_aligned) = dispatcher.join().unwrap()?;
+	evidence.extend(split_aligned_evidence(genome, settings, &split_aligned));
+	Ok((evidence, discordant))
+}
+
+fn bowtie_read_num(line: &str) -> usize {
+	line.split(':').nth(1).unwrap().parse().unwrap()
+}
+
+fn bowtie_read_evidence(genome: &HashMap<String, Vec<u8>>, settings: &Settings,
+	alignments: &[String]) -> Vec<Evidence> {
+
+	let anchors_5p: Vec<Alignment> = alignments.iter()
+		.filter(|line| line.starts_with("5p:"))
+		.map(|line| parse_bowtie_alignment(line)).collect();
+	let anchors_3p: Vec<Alignment> = alignments.iter()
+		.filter(|line| line.starts_with("3p:"))
+		.map(|line| parse_bowtie_alignment(line)).collect();
+	if anchors_5p.is_empty() || anchors_3p.is_empty() { return Vec::new(); }
+
+	// 3' anchor: 3p:READ#:FRAG_ID:FULL_SEQUENCE:MATE_CHR:MATE_POS:
+	let name = alignments.iter().find(|line| line.starts_with("3p:"))
+		.unwrap().split('\t').next().unwrap();
+	let mut anchor_info = name.splitn(5, ':');
+	let num: usize = anchor_info.nth(1).unwrap().parse().unwrap();
+	let frag_id = anchor_info.next().unwrap().as_bytes();
+	let seq = anchor_info.next().unwrap().as_bytes();
+	let mut mate_info = anchor_info.next().unwrap()
+		.trim_end_matches(':').rsplitn(2, ':');
+	let mate_pos: usize = mate_info.next().unwrap().parse().unwrap();
+	let mate_chr = mate_info.next().unwrap();
+	let mate = if mate_pos > 0 { Some((mate_chr, mate_pos)) } else { None };
+
+	let read = AnchoredRead { num, frag_id, seq, mate };
+	read_evidence(genome, settings, &read, &anchors_5p, &anchors_3p)
+}
+
+fn parse_bowtie_alignment(line: &str) -> Alignment {
+	let mut cols = line.split('\t');
+	cols.next();   // Skip first column
+	let strand = cols.next().unwrap() == "+";
+	let chr = cols.next().unwrap().to_string();
+	let pos: usize = cols.next().unwrap().parse().unwrap();
+
+	// The last column lists mismatches as comma-separated descriptors
+	let mismatches = match cols.next() {
+		Some(desc) if !desc.is_empty() => desc.split(',').count(),
+		_ => 0
+	};
+	Alignment { chr, pos, strand, mismatches }
+}
+
+// Like align_anchors_with_bowtie(), but aligns the anchors with the
+// built-in FM-index aligner instead.
+pub fn align_anchors_with_fmindex(genome: &HashMap<String, Vec<u8>>,
+	settings: &Settings, sam_path: &str)
+	-> io::Result<(Vec<Evidence>, Vec<DiscordantPair>)> {
+
+	eprintln!("Building FM-index of the reference genome...");
+	let index = FMIndex::new(genome).map_err(io::Error::other)?;
+
+	let anchor_len = settings.anchor_len;
+	let mut evidence: Vec<Evidence> = Vec::new();
+	let (discordant, split_aligned) = for_each_candidate_read(sam_path,
+		settings, |num, frag_id, seq, mate| {
+		let anchors_5p = index.align(&seq[..anchor_len],
+			settings.anchor_mm, settings.max_placements);
+		if anchors_5p.is_empty() { return Ok(()); }
+		let anchors_3p = index.align(&seq[seq.len() - anchor_len..],
+			settings.anchor_mm, settings.max_placements);
+		if anchors_3p.is_empty() { return Ok(()); }
+
+		let mate = mate.map(|(chr, pos)| (str::from_utf8(chr).unwrap(), pos));
+		let read = AnchoredRead { num, frag_id, seq, mate };
+		evidence.extend(read_evidence(genome, settings, &read, &anchors_5p,
+			&anchors_3p));
+		Ok(())
+	})?;
+	evidence.extend(split_aligned_evidence(genome, settings, &split_aligned));
+	Ok((evidence, discordant))
+}
+
+// Constructs breakpoint evidence for reads whose anchors were placed based
+// on their SA tags. Like anchors aligned by us, both anchors may have at
+// most --anchor-mm mismatches against the genome.
+fn split_aligned_evidence(genome: &HashMap<String, Vec<u8>>,
+	settings: &Settings, reads: &[SplitAlignedRead]) -> Vec<Evidence> {
+
+	let anchor_len = settings.anchor_len;
+	let mut evidence: Vec<Evidence> = Vec::new();
+	for r in reads {
+		let mut anchor_5p = r.anchor_5p.clone();
+		let mut anchor_3p = r.anchor_3p.clone();
+		let mm_5p = anchor_mismatches(genome, &anchor_5p, &r.seq[..anchor_len]);
+		let mm_3p = anchor_mismatches(genome, &anchor_3p,
+			&r.seq[r.seq.len() - anchor_len..]);
+		match (mm_5p, mm_3p) {
+			(Some(mm_5p), Some(mm_3p)) if mm_5p <= settings.anchor_mm &&
+				mm_3p <= settings.anchor_mm => {
+				anchor_5p.mismatches = mm_5p;
+				anchor_3p.mismatches = mm_3p;
+			},
+			_ => continue
+		}
+		let read = AnchoredRead { num: r.num, frag_id: &r.frag_id, seq: &r.seq,
+			mate: r.mate.as_ref().map(|(chr, pos)| (chr.as_str(), *pos)) };
+		evidence.extend(read_evidence(genome, settings, &read, &[anchor_5p],
+			&[anchor_3p]));
+	}
+	evidence
+}
+
+// Counts the mismatches between an anchor sequence and the genome at the
+// given alignment. Returns None if the alignment lies outside the genome.
+fn anchor_mismatches(genome: &HashMap<String, Vec<u8>>, alignment: &Alignment,
+	anchor: &[u8]) -> Option<usize> {
+	let chr_seq = genome.get(&alignment.chr)?;
+	let start = alignment.pos.checked_sub(1)?;
+	let grch = chr_seq.get(start..start + anchor.len())?;
+	let anchor = if alignment.strand { anchor.to_vec() } else { dna::revcomp(anchor) };
+	Some(anchor.iter().zip(grch)
+		.filter(|(a, g)| !a.eq_ignore_ascii_case(g)).count())
+}
+
+// Groups evidence into clusters of reads that support the same
+// rearrangement. Returns the indices of the reads in each cluster.
+// The evidence must be sorted by position.
+pub fn cluster_evidence(evidence: &[Evidence], max_frag_len: usize)
+	-> Vec<Vec<usize>> {
+
+	let mut clusters: Vec<Vec<usize>> = Vec::new();
 	let mut reported = vec![false; evidence.len()];
 	for p in 0..evidence.len() {
 		// We skip reads that were already incorporated into some cluster.
 		if reported[p] { continue; }
 
 		let read = &evidence[p];
-		let mut cluster: Vec<&Evidence> = vec![read];
+		let mut cluster: Vec<usize> = vec![p];
 		for s in p+1..evidence.len() {
 			// We try to add more reads into the cluster until we encounter
 			// the first read that is so far that it cannot possibly belong
@@ -226,34 +592,43 @@ pub fn main() {
 			if evidence[s].mstrand != read.mstrand { continue; }
 			if evidence[s].signature != read.signature { continue; }
 
-			cluster.push(&evidence[s]);
+			cluster.push(s);
 			reported[s] = true;
 		}
+		clusters.push(cluster);
+	}
+	clusters
+}
 
-		/*if read.signature == "CAGAT|ACTTG".as_bytes() {
-			for r in &cluster {
-				println!("Template ID: {}\nFragment signature: {}\nSequence: {}\n", str::from_utf8(&r.frag_id).unwrap(), str::from_utf8(&r.frag_signature).unwrap(), str::from_utf8(&r.sequence).unwrap());
-			}
-		} else {
-			continue;
-		}*/
+// Counts the discordant read pairs supporting the breakpoints of the given
+// read. Both mates must have matching strands, and lie within max_frag_len
+// of their breakpoint on the side of the joined DNA segment. Pairs from
+// fragments that are already represented by split reads in the cluster are
+// not counted. The discordant pairs must be sorted by position.
+fn count_discordant_pairs(discordant: &[DiscordantPair], read: &Evidence,
+	cluster: &[&Evidence], max_frag_len: usize) -> usize {
 
-		if cluster.len() < min_evidence { continue; }
-		cluster = remove_duplicates(cluster);
-		if cluster.len() < min_evidence { continue; }
-
-		print!("{}\t{}\t{}\t\t{}\t{}\t{}\t\t",
-			read.chr, if read.strand { '+' } else { '-' }, read.pos,
-			read.mchr, if read.mstrand { '+' } else { '-' }, read.mpos);
-		for r in 0..cluster.len() {
-			if r > 0 { print!(";"); }
-			print!("{}", str::from_utf8(cluster[r].sequence.as_slice()).unwrap());
-		}
-		println!("\t{}\t", str::from_utf8(&read.signature).unwrap());
-	}
+	// The segment lies to the left of the first breakpoint if its strand
+	// is +, and to the left of the second breakpoint if its strand is -.
+	let on_segment = |pos: usize, bp: usize, segment_left: bool|
+		if segment_left { pos <= bp && bp - pos <= max_frag_len }
+		else { pos >= bp && pos - bp <= max_frag_len };
+
+	let first = (read.chr.as_str(), read.pos.saturating_sub(max_frag_len));
+	let start = discordant.binary_search_by(|d|
+		if (d.chr.as_str(), d.pos) < first { Ordering::Less }
+		else { Ordering::Greater }).unwrap_or_else(|k| k);
+	discordant[start..].iter()
+		.take_while(|d| d.chr == read.chr && d.pos <= read.pos + max_frag_len)
+		.filter(|d| d.strand == read.strand && d.mstrand == read.mstrand &&
+			d.mchr == read.mchr &&
+			on_segment(d.pos, read.pos, read.strand) &&
+			on_segment(d.mpos, read.mpos, !read.mstrand))
+		.filter(|d| cluster.iter().all(|e| e.frag_id != d.frag_id))
+		.count()
 }
 
-fn remove_duplicates(evidence: Vec<&Evidence>) -> Vec<&Evidence> {
+pub fn remove_duplicates(evidence: Vec<&Evidence>) -> Vec<&Evidence> {
 	let mut filtered: Vec<&Evidence> = Vec::new();
 	let mut redundant_with = vec![-1i32; evidence.len()];
 	for a in 0..evidence.len() {
@@ -279,7 +654,7 @@ fn remove_duplicates(evidence: Vec<&Evidence>) -> Vec<&Evidence> {
 		for k in 0..evidence.len() {
 			if redundant_with[k] != a as i32 { continue; }
 			let seq = &evidence[k].sequence;
-			let left_len = seq.iter().position(|c| *c == '|' as u8).unwrap();
+			let left_len = seq.iter().position(|c| *c == b'|').unwrap();
 			let right_len = seq.len() - left_len - 1;
 
 			// Score is primarily determined by the length of the shortest
@@ -296,47 +671,502 @@ fn remove_duplicates(evidence: Vec<&Evidence>) -> Vec<&Evidence> {
 }
 
 
-fn dispatch_reads_to_bowtie(sam_path: &str, bowtie_in: &mut impl Write, anchor_len: usize, count_duplicates: bool) {
+// Calls the given closure with the read number, fragment ID and sequence of
+// every read that is a candidate for split read analysis, along with the
+// chromosome and 1-based position of the read's mate (if the mate is
+// aligned). Candidates are unaligned reads and, if requested, primary
+// alignment records with soft clipped ends. If the clipped end of such a
+// read was aligned elsewhere by the upstream aligner, as listed in its SA
+// tag, the read is returned with both anchors already placed instead of
+// being passed to the closure. Supplementary records are skipped, since
+// the primary record carries the full read sequence. If requested,
+// discordant read pairs are also collected and returned. Errors from reading
+// the alignment file or from the closure stop the iteration.
+fn for_each_candidate_read(sam_path: &str, settings: &Settings,
+	mut f: impl FnMut(usize, &[u8], &[u8], Option<(&[u8], usize)>) -> io::Result<()>)
+	-> io::Result<(Vec<DiscordantPair>, Vec<SplitAlignedRead>)> {
 
-	let mut bam = if sam_path == "-" {
-		bam::Reader::from_stdin().unwrap_or_else(
-			|_| error!("Failed to read BAM file from standard input."))
-	} else {
-		bam::Reader::from_path(&sam_path).unwrap_or_else(
-			|_| error!("Could not open BAM file '{}'", sam_path))
-	};
+	let anchor_len = settings.anchor_len;
+
+	let mut bam = AlignmentReader::open(sam_path, &settings.reference)?;
+	let chr_names: Vec<Vec<u8>> = bam.header().target_names().iter()
+		.map(|name| name.to_vec()).collect();
+
+	let mut discordant: Vec<DiscordantPair> = Vec::new();
+	let mut split_aligned: Vec<SplitAlignedRead> = Vec::new();
+	let mut num = 0;
+	let mut read = bam::Record::new();
+	while next_bam_record(&mut bam, &mut read)? {
+		if settings.discordant_pairs {
+			if let Some(pair) = discordant_pair(&read, &chr_names, settings) {
+				discordant.push(pair);
+			}
+		}
 
-	let mut num_reads_sent = 0;
-	for r in bam.records() {
-		let read = r.unwrap();
-		if read.is_unmapped() == false { continue; }
-		if read.is_duplicate() && count_duplicates == false { continue; }
+		if !read.is_unmapped() && (!settings.soft_clipped ||
+			read.is_secondary() || read.is_supplementary() ||
+			!has_long_soft_clip(&read, anchor_len)) {
+			continue;
+		}
+		if read.is_duplicate() && !settings.count_duplicates { continue; }
 		if read.seq().len() < anchor_len * 2 { continue; }
 
+		let mate = if read.is_paired() && !read.is_mate_unmapped() &&
+			read.mtid() >= 0 {
+			Some((chr_names[read.mtid() as usize].as_slice(),
+				read.mpos() as usize + 1))
+		} else { None };
+
+		num += 1;
+		if !read.is_unmapped() {
+			if let Some((anchor_5p, anchor_3p)) =
+				split_alignment_anchors(&read, &chr_names, anchor_len) {
+				split_aligned.push(SplitAlignedRead {
+					num, frag_id: read.qname().to_vec(), seq: read.seq().as_bytes(),
+					mate: mate.map(|(chr, pos)|
+						(String::from_utf8_lossy(chr).into_owned(), pos)),
+					anchor_5p, anchor_3p
+				});
+				continue;
+			}
+		}
+
+		// Reads never need to be reverse-complemented, since the anchors
+		// are aligned against both strands of the genome.
+		f(num, read.qname(), &read.seq().as_bytes(), mate)?;
+	}
+	Ok((discordant, split_aligned))
+}
+
+// Number of reference bases spanned by an alignment, and the lengths of
+// its clipped ends, in genomic orientation.
+struct AlignedSpan {
+	left_clip: usize,
+	ref_len: usize,
+	right_clip: usize
+}
+
+fn aligned_span(cigar: &str) -> Option<AlignedSpan> {
+	let mut span = AlignedSpan { left_clip: 0, ref_len: 0, right_clip: 0 };
+	let mut len = 0;
+	let mut aligned = false;
+	for c in cigar.bytes() {
+		if c.is_ascii_digit() { len = len * 10 + (c - b'0') as usize; continue; }
+		match c {
+			b'S' | b'H' if aligned => span.right_clip += len,
+			b'S' | b'H' => span.left_clip += len,
+			b'M' | b'D' | b'N' | b'=' | b'X' => { span.ref_len += len; aligned = true; },
+			b'I' | b'P' => aligned = true,
+			_ => return None
+		}
+		len = 0;
+	}
+	if span.ref_len == 0 { None } else { Some(span) }
+}
+
+// Places the anchors of a primary alignment record with a long soft clipped
+// end, if its SA tag lists a supplementary alignment for the clipped end.
+// The aligned part of the record gives the placement of one anchor, and the
+// supplementary alignment that of the other. Anchors are placed relative to
+// the read sequence as stored in the record, and the clipped ends of both
+// alignments are assumed to continue without indels. Mismatches are counted
+// later against the genome.
+fn split_alignment_anchors(read: &bam::Record, chr_names: &[Vec<u8>],
+	anchor_len: usize) -> Option<(Alignment, Alignment)> {
+
+	let sa_tag = read.aux(b"SA")?;
+	let cigar: String = read.cigar().iter()
+		.map(|op| format!("{}{}", op.len(), op.char())).collect();
+	let primary = aligned_span(&cigar)?;
+	let chr = String::from_utf8_lossy(&chr_names[read.tid() as usize]).into_owned();
+	let pos = read.pos() as usize + 1;
+	let first_base = pos.checked_sub(primary.left_clip)?;
+	let last_base = pos + primary.ref_len + primary.right_clip - 1;
+
+	// The clipped end that is aligned by the supplementary alignment
+	let clipped_3p = primary.right_clip >= primary.left_clip;
+	if max(primary.left_clip, primary.right_clip) < anchor_len { return None; }
+
+	// SA:Z:chr,pos,strand,CIGAR,mapQ,NM; with one entry per alignment
+	for entry in str::from_utf8(sa_tag.string()).ok()?.split(';') {
+		let cols: Vec<&str> = entry.split(',').collect();
+		if cols.len() < 4 { continue; }
+		let sa_pos: usize = match cols[1].parse() {
+			Ok(sa_pos) if sa_pos > 0 => sa_pos, _ => continue
+		};
+		let sa = match aligned_span(cols[3]) { Some(sa) => sa, None => continue };
+		let sa_first = match sa_pos.checked_sub(sa.left_clip) {
+			Some(sa_first) => sa_first, None => continue
+		};
+		let sa_last = sa_pos + sa.ref_len + sa.right_clip - 1;
+
+		// The supplementary alignment has the same orientation as the read
+		// sequence if its strand matches the strand of the primary alignment.
+		// It must cover the clipped end of the read, and not the end that
+		// the primary alignment covers.
+		let same_strand = (cols[2] == "+") != read.is_reverse();
+		let (clip_5p, clip_3p) = if same_strand {
+			(sa.left_clip, sa.right_clip)
+		} else { (sa.right_clip, sa.left_clip) };
+		let (sa_clipped, sa_aligned) =
+			if clipped_3p { (clip_5p, clip_3p) } else { (clip_3p, clip_5p) };
+		if sa_aligned >= anchor_len || sa_clipped < anchor_len { continue; }
+
+		// Leftmost genomic position of the anchor that lies at the 5' or
+		// 3' end of the read sequence, in the orientation of the alignment.
+		let anchor_5p_pos = |first: usize, last: usize, same_strand: bool|
+			if same_strand { Some(first) } else { (last + 1).checked_sub(anchor_len) };
+		let anchor_3p_pos = |first: usize, last: usize, same_strand: bool|
+			if same_strand { (last + 1).checked_sub(anchor_len) } else { Some(first) };
+
+		let sa_chr = cols[0].to_string();
+		return if clipped_3p {
+			Some((Alignment { chr, pos: first_base, strand: true, mismatches: 0 },
+				Alignment { chr: sa_chr, pos: anchor_3p_pos(sa_first, sa_last, same_strand)?,
+					strand: same_strand, mismatches: 0 }))
+		} else {
+			Some((Alignment { chr: sa_chr, pos: anchor_5p_pos(sa_first, sa_last, same_strand)?,
+					strand: same_strand, mismatches: 0 },
+				Alignment { chr, pos: anchor_3p_pos(first_base, last_base, true)?,
+					strand: true, mismatches: 0 }))
+		};
+	}
+	None
+}
+
+// Returns the discordant read pair that the given record belongs to, if the
+// pair is discordant. Each pair is only returned for the mate with the lower
+// coordinate. Pairs where either mate has mapping quality zero are ignored.
+fn discordant_pair(read: &bam::Record, chr_names: &[Vec<u8>],
+	settings: &Settings) -> Option<DiscordantPair> {
+
+	if !read.is_paired() || read.is_unmapped() ||
+		read.is_mate_unmapped() || read.is_secondary() ||
+		read.is_supplementary() || read.mapq() == 0 { return None; }
+	if read.is_duplicate() && !settings.count_duplicates { return None; }
+	if let Some(mq) = read.aux(b"MQ") {
+		if mq.integer() == 0 { return None; }
+	}
+
+	let chr = str::from_utf8(&chr_names[read.tid() as usize]).unwrap();
+	let mchr = str::from_utf8(&chr_names[read.mtid() as usize]).unwrap();
+	let pos = read.pos() as usize + 1;
+	let mpos = read.mpos() as usize + 1;
+	if (chr, pos) > (mchr, mpos) { return None; }
+	if (chr, pos) == (mchr, mpos) && !read.is_first_in_template() {
+		return None;
+	}
+
+	// A forward strand mate points towards a breakpoint on its right side,
+	// so the DNA segment it belongs to lies to the left of the breakpoint.
+	let strand = !read.is_reverse();
+	let mstrand = read.is_mate_reverse();
+	if chr == mchr && strand && mstrand &&
+		mpos - pos <= settings.max_frag_len { return None; }
+
+	Some(DiscordantPair {
+		chr: chr.to_string(), pos, strand,
+		mchr: mchr.to_string(), mpos, mstrand,
+		frag_id: read.qname().to_vec()
+	})
+}
+
+// Returns true if the read carries its full sequence (no hard clipping), and
+// has a soft clipped end long enough to be aligned as an anchor. These reads
+// typically overlap a breakpoint, with the clipped part coming from the
+// other side of the junction.
+fn has_long_soft_clip(read: &bam::Record, anchor_len: usize) -> bool {
+	let cigar = read.cigar();
+	for op in cigar.iter() {
+		if let Cigar::HardClip(_) = op { return false; }
+	}
+	let clip_len = |op: Option<&Cigar>| match op {
+		Some(Cigar::SoftClip(len)) => *len as usize, _ => 0
+	};
+	clip_len(cigar.iter().next()) >= anchor_len ||
+		clip_len(cigar.iter().last()) >= anchor_len
+}
+
+fn dispatch_reads_to_bowtie(sam_path: &str, bowtie_in: &mut impl Write,
+	settings: &Settings)
+	-> io::Result<(Vec<DiscordantPair>, Vec<SplitAlignedRead>)> {
+
+	let anchor_len = settings.anchor_len;
+	for_each_candidate_read(sam_path, settings, |num, qname, seq, mate| {
+
 		// Any ':' characters in the fragment ID must be removed here
 		// since ':' is used as a delimiter in our anchor descriptors.
-		let mut frag_id = read.qname().to_vec();
-		for k in 0..frag_id.len() {
-			if frag_id[k] == b':' { frag_id[k] = b'_'; }
+		let mut frag_id = qname.to_vec();
+		for c in frag_id.iter_mut() {
+			if *c == b':' { *c = b'_'; }
 		}
 
-		// Unaligned reads never need to be reverse-complemented
-		let seq = read.seq().as_bytes();
+		// 5' anchor: >5p:READ#:
+		writeln!(bowtie_in, ">5p:{}:", num)?;
+		bowtie_in.write_all(&seq[..anchor_len])?;
 
-		num_reads_sent += 1;
+		// 3' anchor: >3p:READ#:FRAG_ID:FULL_SEQUENCE:MATE_CHR:MATE_POS:
+		// The mate locus comes last since chromosome names may contain ':'.
+		write!(bowtie_in, "\n>3p:{}:", num)?;
+		bowtie_in.write_all(&frag_id)?;
+		write!(bowtie_in, ":")?;
+		bowtie_in.write_all(seq)?;
+		match mate {
+			Some((chr, pos)) => {
+				write!(bowtie_in, ":")?;
+				bowtie_in.write_all(chr)?;
+				writeln!(bowtie_in, ":{}:", pos)?;
+			},
+			None => writeln!(bowtie_in, ":*:0:")?
+		}
+		bowtie_in.write_all(&seq[(seq.len() - anchor_len)..])?;
+		writeln!(bowtie_in)
+	})
+}
 
-		// 5' anchor: >5p:READ#:
-		write!(bowtie_in, ">5p:{}:\n", num_reads_sent).unwrap();
-		bowtie_in.write_all(&seq[..anchor_len]).unwrap();
-
-		// 3' anchor: >3p:READ#:FRAG_ID:FULL_SEQUENCE:
-		write!(bowtie_in, "\n>3p:{}:", num_reads_sent).unwrap();
-		bowtie_in.write_all(&frag_id).unwrap();
-		write!(bowtie_in, ":").unwrap();
-		bowtie_in.write_all(&seq).unwrap();
-		write!(bowtie_in, ":\n").unwrap();
-		bowtie_in.write_all(&seq[(seq.len() - anchor_len)..]).unwrap();
-		writeln!(bowtie_in).unwrap();
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::common::test_util;
+
+	fn test_genome() -> HashMap<String, Vec<u8>> { test_util::test_genome(5000) }
+
+	fn anchor(chr: &str, pos: usize) -> Alignment {
+		Alignment { chr: chr.to_string(), pos, strand: true, mismatches: 0 }
 	}
-}
 
+	fn test_settings() -> Settings {
+		Settings { anchor_len: 20, anchor_mm: 0, max_placements: 2,
+			max_frag_len: 500, min_evidence: 2, count_duplicates: false,
+			soft_clipped: true, discordant_pairs: false, reference: String::new() }
+	}
+
+	// Read joining chr1:751-800 to chr2:2001-2050, with the given bases
+	// inserted between the flanks.
+	fn junction_read(genome: &HashMap<String, Vec<u8>>, insertion: &[u8])
+		-> Vec<u8> {
+		let mut read = genome["chr1"][750..800].to_vec();
+		read.extend(insertion);
+		read.extend(&genome["chr2"][2000..2050]);
+		read
+	}
+
+	#[test]
+	fn junction_with_insertion() {
+		let genome = test_genome();
+		let read = junction_read(&genome, b"GATTACA");
+		let e = junction_evidence(&genome, 20, b"frag", &read,
+			&anchor("chr1", 751), &anchor("chr2", 2031)).unwrap();
+		assert_eq!((e.chr.as_str(), e.pos, e.strand), ("chr1", 800, true));
+		assert_eq!((e.mchr.as_str(), e.mpos, e.mstrand), ("chr2", 2001, true));
+		assert_eq!(e.insertion, b"GATTACA".to_vec());
+		assert!(e.homology.is_empty());
+		let sequence = String::from_utf8(e.sequence).unwrap();
+		assert!(sequence.contains("|gattaca"));
+	}
+
+	#[test]
+	fn junction_with_sequencing_error() {
+		let genome = test_genome();
+		let mut read = junction_read(&genome, b"");
+		read[46] = if read[46] == b'A' { b'C' } else { b'A' };
+		let e = junction_evidence(&genome, 20, b"frag", &read,
+			&anchor("chr1", 751), &anchor("chr2", 2031)).unwrap();
+		assert_eq!((e.pos, e.mpos), (800, 2001));
+		assert!(e.insertion.is_empty());
+	}
+
+	#[test]
+	fn junction_with_microhomology() {
+		let mut genome = test_genome();
+		// The first two bases of the right flank also follow the left flank,
+		// and the last base of the left flank differs from the base that
+		// precedes the right flank.
+		let chr1 = genome["chr1"].clone();
+		let chr2 = genome.get_mut("chr2").unwrap();
+		chr2[2000] = chr1[800];
+		chr2[2001] = chr1[801];
+		chr2[1999] = if chr1[799] == b'A' { b'C' } else { b'A' };
+		let read = junction_read(&genome, b"");
+		let e = junction_evidence(&genome, 20, b"frag", &read,
+			&anchor("chr1", 751), &anchor("chr2", 2031)).unwrap();
+		assert_eq!((e.chr.as_str(), e.pos), ("chr1", 800));
+		assert_eq!((e.mchr.as_str(), e.mpos), ("chr2", 2001));
+		assert_eq!(e.homology, genome["chr2"][2000..2002].to_vec());
+		assert!(e.insertion.is_empty());
+	}
+
+	fn pair(pos: usize, strand: bool, mpos: usize, mstrand: bool,
+		frag_id: &[u8]) -> DiscordantPair {
+		DiscordantPair { chr: "chr1".to_string(), pos, strand,
+			mchr: "chr2".to_string(), mpos, mstrand, frag_id: frag_id.to_vec() }
+	}
+
+	#[test]
+	fn discordant_pairs_on_segment_side() {
+		// The junction joins chr1 up to 800 with chr2 from 2001 onwards,
+		// so supporting pairs have a forward mate left of chr1:800 and a
+		// reverse mate right of chr2:2001.
+		let genome = test_genome();
+		let read = junction_read(&genome, b"");
+		let e = junction_evidence(&genome, 20, b"split", &read,
+			&anchor("chr1", 751), &anchor("chr2", 2031)).unwrap();
+		let mut discordant = vec![
+			pair(400, true, 2300, true, b"a"),
+			pair(500, true, 2200, true, b"split"),   // Same fragment as split read
+			pair(600, true, 1800, true, b"b"),       // Mate #2 left of breakpoint
+			pair(700, false, 2100, true, b"c"),      // Wrong strand
+			pair(790, true, 2010, true, b"d"),
+			pair(850, true, 2100, true, b"e"),       // Mate #1 right of breakpoint
+			pair(790, true, 2900, true, b"f")        // Mate #2 too far
+		];
+		discordant.sort_by(|a, b| (&a.chr, a.pos).cmp(&(&b.chr, b.pos)));
+		assert_eq!(count_discordant_pairs(&discordant, &e, &[&e], 500), 2);
+		assert_eq!(count_discordant_pairs(&discordant, &e, &[], 500), 3);
+	}
+
+	#[test]
+	fn placement_resolved_by_mate() {
+		// The right flank of the junction is duplicated at chr2:4001-4050
+		let mut genome = test_genome();
+		let chr2 = genome.get_mut("chr2").unwrap();
+		let flank = chr2[2000..2050].to_vec();
+		chr2[4000..4050].copy_from_slice(&flank);
+		let read = junction_read(&genome, b"");
+		let settings = test_settings();
+		let anchors_5p = [anchor("chr1", 751)];
+		let anchors_3p = [anchor("chr2", 2031), anchor("chr2", 4031)];
+
+		let unpaired = AnchoredRead { num: 1, frag_id: b"frag", seq: &read, mate: None };
+		let evidence = read_evidence(&genome, &settings, &unpaired,
+			&anchors_5p, &anchors_3p);
+		assert_eq!(evidence.len(), 2);
+		assert!(evidence.iter().all(|e| e.ambiguous));
+
+		let paired = AnchoredRead { mate: Some(("chr2", 2300)), ..unpaired };
+		let evidence = read_evidence(&genome, &settings, &paired,
+			&anchors_5p, &anchors_3p);
+		assert_eq!(evidence.len(), 1);
+		assert_eq!(evidence[0].mpos, 2001);
+		assert!(!evidence[0].ambiguous);
+	}
+
+	// Primary alignment record with the given SA tag, where tid 0 is chr1
+	// and tid 1 is chr2.
+	fn primary_record(cigar: &[Cigar], seq: &[u8], tid: i32, pos: i32,
+		reverse: bool, sa_tag: &[u8]) -> bam::Record {
+		// The qname length keeps the CIGAR data 4-byte aligned
+		let mut record = bam::Record::new();
+		record.set(b"frag001", &bam::record::CigarString(cigar.to_vec()), seq,
+			&vec![30; seq.len()]);
+		record.set_tid(tid);
+		record.set_pos(pos);
+		record.set_flags(if reverse { 16 } else { 0 });
+		record.push_aux(b"SA", &bam::record::Aux::String(sa_tag));
+		record
+	}
+
+	fn split_aligned_read(record: &bam::Record) -> Option<SplitAlignedRead> {
+		let chr_names = vec![b"chr1".to_vec(), b"chr2".to_vec()];
+		let (anchor_5p, anchor_3p) =
+			split_alignment_anchors(record, &chr_names, 20)?;
+		Some(SplitAlignedRead { num: 1, frag_id: record.qname().to_vec(),
+			seq: record.seq().as_bytes(), mate: None, anchor_5p, anchor_3p })
+	}
+
+	#[test]
+	fn evidence_from_sa_tags() {
+		let genome = test_genome();
+		let read = junction_read(&genome, b"");
+
+		// Primary alignment on chr1 with the chr2 flank soft clipped, and
+		// vice versa. Both records give the same junction.
+		let records = [
+			primary_record(&[Cigar::Match(50), Cigar::SoftClip(50)], &read,
+				0, 750, false, b"chr2,2001,+,50S50M,60,0;"),
+			primary_record(&[Cigar::SoftClip(50), Cigar::Match(50)], &read,
+				1, 2000, false, b"chr1,751,+,50M50S,60,0;")
+		];
+		for record in &records {
+			let r = split_aligned_read(record).unwrap();
+			let evidence = split_aligned_evidence(&genome, &test_settings(), &[r]);
+			assert_eq!(evidence.len(), 1);
+			let e = &evidence[0];
+			assert_eq!((e.chr.as_str(), e.pos, e.strand), ("chr1", 800, true));
+			assert_eq!((e.mchr.as_str(), e.mpos, e.mstrand), ("chr2", 2001, true));
+		}
+
+		// Junction between chr1 and the minus strand of chr2, with the primary
+		// alignment on the minus strand of chr2. The record stores the read
+		// in reverse complement.
+		let mut read = genome["chr1"][750..800].to_vec();
+		read.extend(dna::revcomp(&genome["chr2"][2000..2050]));
+		let record = primary_record(&[Cigar::Match(50), Cigar::SoftClip(50)],
+			&dna::revcomp(&read), 1, 2000, true, b"chr1,751,+,50M50S,60,0;");
+		let r = split_aligned_read(&record).unwrap();
+		let evidence = split_aligned_evidence(&genome, &test_settings(), &[r]);
+		assert_eq!(evidence.len(), 1);
+
+		// Same evidence as when the anchors are aligned by the FM-index
+		let index = FMIndex::new(&genome).unwrap();
+		let seq = record.seq().as_bytes();
+		let anchored = AnchoredRead { num: 1, frag_id: b"frag001", seq: &seq, mate: None };
+		let aligned = read_evidence(&genome, &test_settings(), &anchored,
+			&index.align(&seq[..20], 0, 1), &index.align(&seq[80..], 0, 1));
+		assert_eq!(aligned.len(), 1);
+		let (e, a) = (&evidence[0], &aligned[0]);
+		assert_eq!((&e.chr, e.pos, e.strand, &e.mchr, e.mpos, e.mstrand),
+			(&a.chr, a.pos, a.strand, &a.mchr, a.mpos, a.mstrand));
+		assert_eq!((e.chr.as_str(), e.mchr.as_str(), e.mstrand), ("chr1", "chr2", false));
+		assert_eq!(e.sequence, a.sequence);
+
+		// Supplementary alignments that do not cover the clipped end, or
+		// place anchors with too many mismatches, give no evidence.
+		let record = primary_record(&[Cigar::Match(50), Cigar::SoftClip(50)], &read,
+			0, 750, false, b"chr2,2001,+,50M50S,60,0;");
+		assert!(split_aligned_read(&record).is_none());
+		let record = primary_record(&[Cigar::Match(50), Cigar::SoftClip(50)], &read,
+			0, 750, false, b"chr2,3001,+,50S50M,60,0;");
+		let r = split_aligned_read(&record).unwrap();
+		assert!(split_aligned_evidence(&genome, &test_settings(), &[r]).is_empty());
+	}
+
+	#[test]
+	fn junction_near_chromosome_start() {
+		// The first breakpoint lies 25 bp from the start of chr1, too close
+		// for a reference signature, but the evidence is kept.
+		let genome = test_genome();
+		let mut read = genome["chr1"][..25].to_vec();
+		read.extend(&genome["chr2"][2000..2050]);
+		let e = junction_evidence(&genome, 20, b"frag", &read,
+			&anchor("chr1", 1), &anchor("chr2", 2031)).unwrap();
+		assert_eq!((e.pos, e.mpos), (25, 2001));
+		assert!(e.ref_signature_1.is_empty());
+		assert_eq!(e.ref_signature_2.len(), 2 * REF_FLANK_LEN + 1);
+	}
+
+	#[test]
+	fn call_rearrangement_from_junction_reads() {
+		// Two distinct reads support the same junction, which is reported
+		// once both count towards the minimum evidence.
+		let genome = test_genome();
+		let first = junction_read(&genome, b"");
+		let mut second = genome["chr1"][740..800].to_vec();
+		second.extend(&genome["chr2"][2000..2040]);
+		let evidence = || vec![
+			junction_evidence(&genome, 20, b"frag1", &first,
+				&anchor("chr1", 751), &anchor("chr2", 2031)).unwrap(),
+			junction_evidence(&genome, 20, b"frag2", &second,
+				&anchor("chr1", 741), &anchor("chr2", 2021)).unwrap()];
+
+		let mut settings = test_settings();
+		let svs = call_rearrangements(evidence(), Vec::new(), &settings);
+		assert_eq!(svs.len(), 1);
+		assert_eq!((svs[0].chr.as_str(), svs[0].pos, svs[0].strand), ("chr1", 800, true));
+		assert_eq!((svs[0].mchr.as_str(), svs[0].mpos, svs[0].mstrand), ("chr2", 2001, true));
+		assert_eq!(svs[0].reads.len(), 2);
+
+		settings.min_evidence = 3;
+		assert!(call_rearrangements(evidence(), Vec::new(), &settings).is_empty());
+	}
+}
@@ -0,0 +1,306 @@
+
+use crate::common::SvRecord;
+use crate::gtf::Transcript;
+use bio::alphabets::dna;
+use std::collections::HashMap;
+
+// Number of codons from both fusion partners in the junction peptide
+const PEPTIDE_FLANK_CODONS: usize = 10;
+
+// Standard genetic code, with codons ordered by their bases in TCAG order
+const GENETIC_CODE: &[u8; 64] =
+	b"FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG";
+
+fn codon_base_index(nuc: u8) -> Option<usize> {
+	match nuc.to_ascii_uppercase() {
+		b'T' => Some(0), b'C' => Some(1), b'A' => Some(2), b'G' => Some(3),
+		_ => None
+	}
+}
+
+// Translates a coding sequence into amino acids, stopping after the first
+// stop codon. Codons with ambiguous nucleotides are translated as 'X'.
+pub fn translate(seq: &[u8]) -> String {
+	let mut peptide = String::new();
+	for codon in seq.chunks(3) {
+		if codon.len() < 3 { break; }
+		let aa = match (codon_base_index(codon[0]), codon_base_index(codon[1]),
+			codon_base_index(codon[2])) {
+			(Some(a), Some(b), Some(c)) => GENETIC_CODE[a * 16 + b * 4 + c] as char,
+			_ => 'X'
+		};
+		peptide.push(aa);
+		if aa == '*' { break; }
+	}
+	peptide
+}
+
+// Exonic positions of a transcript in the direction of transcription, along
+// with the indices of the first and last coding base (if any).
+struct TranscriptLayout {
+	positions: Vec<u32>,
+	cds: Option<(usize, usize)>
+}
+
+impl TranscriptLayout {
+	fn new(t: &Transcript) -> TranscriptLayout {
+		let mut positions: Vec<u32> = t.exons.iter()
+			.flat_map(|(start, end)| *start..=*end).collect();
+		if !t.strand { positions.reverse(); }
+		let cds = if t.cds.is_empty() { None } else {
+			let first = if t.strand { t.cds[0].0 } else { t.cds[t.cds.len() - 1].1 };
+			let last = if t.strand { t.cds[t.cds.len() - 1].1 } else { t.cds[0].0 };
+			match (positions.iter().position(|p| *p == first),
+				positions.iter().position(|p| *p == last)) {
+				(Some(first), Some(last)) => Some((first, last)),
+				_ => None
+			}
+		};
+		TranscriptLayout { positions, cds }
+	}
+
+	// Number of exonic bases that lie upstream of the given genomic position
+	// in the direction of transcription, including the position itself if
+	// inclusive is true.
+	fn upstream_len(&self, pos: u32, strand: bool, inclusive: bool) -> usize {
+		self.positions.iter().take_while(|p| match (strand, inclusive) {
+			(true, true) => **p <= pos, (true, false) => **p < pos,
+			(false, true) => **p >= pos, (false, false) => **p > pos
+		}).count()
+	}
+
+	fn sequence(&self, chr_seq: &[u8], range: std::ops::Range<usize>,
+		strand: bool) -> Vec<u8> {
+		self.positions[range].iter().map(|p| {
+			let nuc = chr_seq.get(*p as usize - 1).cloned().unwrap_or(b'N');
+			if strand { nuc.to_ascii_uppercase() }
+			else { dna::complement(nuc).to_ascii_uppercase() }
+		}).collect()
+	}
+}
+
+// Predicted gene fusion arising from a rearrangement
+pub struct Fusion {
+	pub gene_5p: String,
+	pub gene_3p: String,
+	pub frame: &'static str,      // in_frame, out_of_frame, 5UTR, 3UTR or noncoding
+	pub peptide: Option<String>   // Junction peptide, with '|' at the junction
+}
+
+// Determines which of two genes, lying at the first and second breakpoint
+// of a rearrangement, would be the 5' partner of a fusion transcript.
+// Returns true if the gene at the first breakpoint is the 5' partner, and
+// None if the orientation of the genes is incompatible with a fusion.
+pub fn first_is_5p(sv: &SvRecord, gene_strand_1: bool, gene_strand_2: bool)
+	-> Option<bool> {
+	// Gene #1 is transcribed towards the junction if its strand matches the
+	// side of the breakpoint that its DNA segment lies on. Gene #2 is
+	// transcribed away from the junction if its segment extends to the
+	// right of the breakpoint on the forward strand, or vice versa.
+	match (sv.strand == gene_strand_1, sv.mstrand == gene_strand_2) {
+		(true, true) => Some(true),
+		(false, false) => Some(false),
+		_ => None
+	}
+}
+
+// Predicts the gene fusion produced by a rearrangement whose breakpoints lie
+// in the given transcripts. The 5' partner is the gene that is transcribed
+// towards the junction, and the 3' partner must then be transcribed away
+// from it. Returns None if the orientation of the genes is incompatible
+// with a fusion transcript. The fusion transcript is assumed to join the
+// last exonic base of the 5' partner upstream of its breakpoint to the first
+// exonic base of the 3' partner downstream of its breakpoint, so that
+// intronic breakpoints join whole exons.
+pub fn predict_fusion(sv: &SvRecord, transcript_1: &Transcript,
+	transcript_2: &Transcript, genome: &HashMap<String, Vec<u8>>)
+	-> Option<Fusion> {
+
+	let (t5, pos5, t3, pos3) =
+		match first_is_5p(sv, transcript_1.strand, transcript_2.strand)? {
+		true => (transcript_1, sv.pos, transcript_2, sv.mpos),
+		false => (transcript_2, sv.mpos, transcript_1, sv.pos)
+	};
+
+	let fusion = Fusion {
+		gene_5p: t5.gene.clone(), gene_3p: t3.gene.clone(),
+		frame: "noncoding", peptide: None
+	};
+	let layout_5p = TranscriptLayout::new(t5);
+	let layout_3p = TranscriptLayout::new(t3);
+	let (cds_5p, cds_3p) = match (layout_5p.cds, layout_3p.cds) {
+		(Some(cds_5p), Some(cds_3p)) => (cds_5p, cds_3p),
+		_ => return Some(fusion)
+	};
+
+	// The 5' partner keeps its exonic bases up to and including its
+	// breakpoint, and the 3' partner those from its breakpoint onwards.
+	let kept_5p = layout_5p.upstream_len(pos5 as u32, t5.strand, true);
+	let junction_3p = layout_3p.upstream_len(pos3 as u32, t3.strand, false);
+	Some(predict_frame(fusion, (&layout_5p, cds_5p, kept_5p, t5),
+		(&layout_3p, cds_3p, junction_3p, t3), genome))
+}
+
+// Determines the reading frame of a fusion transcript that keeps the first
+// kept_5p exonic bases of the 5' partner, followed by the exonic bases of
+// the 3' partner starting from index junction_3p.
+fn predict_frame(mut fusion: Fusion,
+	(layout_5p, (cds_start_5p, cds_end_5p), kept_5p, t5):
+		(&TranscriptLayout, (usize, usize), usize, &Transcript),
+	(layout_3p, (cds_start_3p, cds_end_3p), junction_3p, t3):
+		(&TranscriptLayout, (usize, usize), usize, &Transcript),
+	genome: &HashMap<String, Vec<u8>>) -> Fusion {
+
+	// No fusion protein if the 5' partner contributes no coding sequence,
+	// or if its coding sequence is kept in full or joined past the coding
+	// sequence of the 3' partner.
+	if kept_5p <= cds_start_5p { fusion.frame = "5UTR"; return fusion; }
+	if kept_5p > cds_end_5p || junction_3p > cds_end_3p {
+		fusion.frame = "3UTR"; return fusion;
+	}
+
+	// Coding bases contributed by the 5' partner, and the position of the
+	// junction relative to the reading frame of the 3' partner. Untranslated
+	// bases of the 3' partner that precede its coding sequence are read in
+	// the frame of the 5' partner.
+	let coding_5p = kept_5p - cds_start_5p;
+	let offset_3p = junction_3p as i64 - cds_start_3p as i64;
+	fusion.frame = if (coding_5p as i64 - offset_3p).rem_euclid(3) == 0 {
+		"in_frame" } else { "out_of_frame" };
+
+	let (chr_5p, chr_3p) = match (genome.get(&t5.chr), genome.get(&t3.chr)) {
+		(Some(chr_5p), Some(chr_3p)) => (chr_5p, chr_3p),
+		_ => return fusion
+	};
+	let partial = coding_5p % 3;
+	let full_codons = std::cmp::min(coding_5p / 3, PEPTIDE_FLANK_CODONS);
+	let start_5p = kept_5p - partial - 3 * full_codons;
+	let mut seq = layout_5p.sequence(chr_5p, start_5p..kept_5p, t5.strand);
+	let end_3p = std::cmp::min(layout_3p.positions.len(),
+		junction_3p + 3 * PEPTIDE_FLANK_CODONS + 3 - partial);
+	seq.extend(layout_3p.sequence(chr_3p, junction_3p..end_3p, t3.strand));
+
+	let peptide = translate(&seq);
+	let split = std::cmp::min(full_codons, peptide.len());
+	fusion.peptide = Some(format!("{}|{}", &peptide[..split], &peptide[split..]));
+	fusion
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn rearrangement(chr: &str, strand: bool, pos: usize, mchr: &str,
+		mstrand: bool, mpos: usize) -> SvRecord {
+		SvRecord {
+			chr: chr.to_string(), strand, pos, features: String::new(),
+			mchr: mchr.to_string(), mstrand, mpos, mfeatures: String::new(),
+			reads: Vec::new(), signature: String::new(), notes: String::new(),
+			extra: Vec::new()
+		}
+	}
+
+	fn transcript(gene: &str, chr: &str, strand: bool, exons: &[(u32, u32)],
+		cds: &[(u32, u32)]) -> Transcript {
+		Transcript {
+			id: format!("{}-201", gene), gene: gene.to_string(),
+			chr: chr.to_string(), strand, canonical: true,
+			exons: exons.to_vec(), cds: cds.to_vec()
+		}
+	}
+
+	// Chromosomes of 400 bp, with the given sequences placed at 1-based
+	// positions.
+	fn genome(chr1: &[(usize, &[u8])], chr2: &[(usize, &[u8])])
+		-> HashMap<String, Vec<u8>> {
+		let mut genome = HashMap::new();
+		for (chr, seqs) in [("chr1", chr1), ("chr2", chr2)] {
+			let mut seq = vec![b'C'; 400];
+			for (pos, s) in seqs {
+				seq[pos - 1..pos - 1 + s.len()].copy_from_slice(s);
+			}
+			genome.insert(chr.to_string(), seq);
+		}
+		genome
+	}
+
+	const EXONS: &[(u32, u32)] = &[(101, 130), (201, 230)];
+
+	#[test]
+	fn fusion_orientation() {
+		let sv = |strand, mstrand| rearrangement("chr1", strand, 150, "chr2", mstrand, 150);
+		for (gene_strand_1, gene_strand_2) in
+			[(true, true), (true, false), (false, true), (false, false)] {
+			let (g1, g2) = (gene_strand_1, gene_strand_2);
+			assert_eq!(first_is_5p(&sv(g1, g2), g1, g2), Some(true));
+			assert_eq!(first_is_5p(&sv(!g1, !g2), g1, g2), Some(false));
+			assert_eq!(first_is_5p(&sv(g1, !g2), g1, g2), None);
+			assert_eq!(first_is_5p(&sv(!g1, g2), g1, g2), None);
+		}
+	}
+
+	#[test]
+	fn in_frame_fusion_on_plus_strands() {
+		let genome = genome(&[(101, &b"GCT".repeat(10))], &[(201, &b"TGG".repeat(10))]);
+		let gene_a = transcript("A", "chr1", true, EXONS, EXONS);
+		let gene_b = transcript("B", "chr2", true, EXONS, EXONS);
+		let sv = rearrangement("chr1", true, 150, "chr2", true, 150);
+		let fusion = predict_fusion(&sv, &gene_a, &gene_b, &genome).unwrap();
+		assert_eq!((fusion.gene_5p.as_str(), fusion.gene_3p.as_str()), ("A", "B"));
+		assert_eq!(fusion.frame, "in_frame");
+		assert_eq!(fusion.peptide.unwrap(), "AAAAAAAAAA|WWWWWWWWWW");
+
+		// A 3' partner whose first exon is one base longer shifts the frame
+		let gene_b = transcript("B", "chr2", true, &[(101, 131), (201, 230)],
+			&[(101, 131), (201, 230)]);
+		let fusion = predict_fusion(&sv, &gene_a, &gene_b, &genome).unwrap();
+		assert_eq!(fusion.frame, "out_of_frame");
+	}
+
+	#[test]
+	fn minus_strand_5p_partner() {
+		// Gene A is transcribed from chr1:230 towards chr1:101, so its first
+		// exon reads GCT repeats on the minus strand.
+		let genome = genome(&[(201, &b"AGC".repeat(10))], &[(201, &b"TGG".repeat(10))]);
+		let gene_a = transcript("A", "chr1", false, EXONS, EXONS);
+		let gene_b = transcript("B", "chr2", true, EXONS, EXONS);
+		let sv = rearrangement("chr1", false, 150, "chr2", true, 150);
+		let fusion = predict_fusion(&sv, &gene_a, &gene_b, &genome).unwrap();
+		assert_eq!((fusion.gene_5p.as_str(), fusion.gene_3p.as_str()), ("A", "B"));
+		assert_eq!(fusion.frame, "in_frame");
+		assert_eq!(fusion.peptide.unwrap(), "AAAAAAAAAA|WWWWWWWWWW");
+
+		// With the genes in the other orientation, B is the 5' partner
+		let sv = rearrangement("chr1", true, 150, "chr2", false, 150);
+		let fusion = predict_fusion(&sv, &gene_a, &gene_b, &genome).unwrap();
+		assert_eq!((fusion.gene_5p.as_str(), fusion.gene_3p.as_str()), ("B", "A"));
+
+		let sv = rearrangement("chr1", false, 150, "chr2", false, 150);
+		assert!(predict_fusion(&sv, &gene_a, &gene_b, &genome).is_none());
+	}
+
+	#[test]
+	fn untranslated_fusions() {
+		let genome = genome(&[], &[]);
+		let gene_a = transcript("A", "chr1", true,
+			&[(101, 130), (201, 230), (301, 330)], &[(210, 230), (301, 320)]);
+		let gene_b = transcript("B", "chr2", true, EXONS, EXONS);
+		let frame = |pos, mpos| predict_fusion(
+			&rearrangement("chr1", true, pos, "chr2", true, mpos),
+			&gene_a, &gene_b, &genome).unwrap().frame;
+		assert_eq!(frame(150, 150), "5UTR");
+		assert_eq!(frame(250, 150), "in_frame");
+		assert_eq!(frame(325, 150), "3UTR");
+
+		// Joined past the end of the coding sequence of the 3' partner
+		let gene_b = transcript("B", "chr2", true, EXONS, &[(101, 130), (201, 220)]);
+		let fusion = predict_fusion(&rearrangement("chr1", true, 250, "chr2", true, 225),
+			&gene_a, &gene_b, &genome).unwrap();
+		assert_eq!(fusion.frame, "3UTR");
+
+		let gene_b = transcript("B", "chr2", true, EXONS, &[]);
+		let fusion = predict_fusion(&rearrangement("chr1", true, 250, "chr2", true, 150),
+			&gene_a, &gene_b, &genome).unwrap();
+		assert_eq!(fusion.frame, "noncoding");
+	}
+}
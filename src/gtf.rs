@@ -0,0 +1,253 @@
+
+use crate::common::{FileReader, ParseError};
+use std::io;
+use crate::annotate::{Feature, Interval};
+use std::collections::{HashMap, HashSet};
+use std::cmp::{min, max};
+
+// Transcript model read from a GTF or GFF3 file. Exons and coding regions
+// are 1-based inclusive intervals sorted by genomic position, regardless of
+// the strand of the transcript.
+#[derive(Debug, Clone)]
+pub struct Transcript {
+	pub id: String,
+	pub gene: String,
+	pub chr: String,
+	pub strand: bool,
+	pub canonical: bool,      // Tagged as Ensembl canonical or MANE Select
+	pub exons: Vec<(u32, u32)>,
+	pub cds: Vec<(u32, u32)>
+}
+
+impl Interval for Transcript {
+	fn chr(&self) -> &str { &self.chr }
+	fn start(&self) -> u32 { self.exons[0].0 }
+	fn end(&self) -> u32 { self.exons[self.exons.len() - 1].1 }
+}
+
+impl Transcript {
+	pub fn exonic_len(&self) -> u32 {
+		self.exons.iter().map(|(start, end)| end - start + 1).sum()
+	}
+
+	// Converts an index into the sorted exons into an exon number that
+	// follows the direction of transcription.
+	fn exon_number(&self, k: usize) -> usize {
+		if self.strand { k + 1 } else { self.exons.len() - k }
+	}
+
+	// Describes the part of the transcript that a genomic position lies in:
+	// exon_N, intron_N, 5UTR_exon_N or 3UTR_exon_N. Exons and introns are
+	// numbered in the direction of transcription, so intron N follows
+	// exon N. Returns None if the position lies outside the transcript.
+	pub fn region(&self, pos: u32) -> Option<String> {
+		if pos < self.start() || pos > self.end() { return None; }
+		for (k, (start, end)) in self.exons.iter().enumerate() {
+			if pos > *end { continue; }
+			if pos < *start {
+				// Intron between exons #k-1 and #k (in genomic order)
+				let number = min(self.exon_number(k - 1), self.exon_number(k));
+				return Some(format!("intron_{}", number));
+			}
+			let number = self.exon_number(k);
+			if self.cds.is_empty() { return Some(format!("exon_{}", number)); }
+			let cds_start = self.cds[0].0;
+			let cds_end = self.cds[self.cds.len() - 1].1;
+			let utr = if pos < cds_start {
+				if self.strand { "5UTR_" } else { "3UTR_" }
+			} else if pos > cds_end {
+				if self.strand { "3UTR_" } else { "5UTR_" }
+			} else { "" };
+			return Some(format!("{}exon_{}", utr, number));
+		}
+		None
+	}
+}
+
+// Parses the attribute column of a GTF (key "value";) or GFF3 (key=value;)
+// line. Values of repeated keys (such as GTF tags) are joined with commas.
+fn parse_attributes(text: &str) -> HashMap<String, String> {
+	let mut attributes: HashMap<String, String> = HashMap::new();
+	for attr in text.split(';') {
+		let attr = attr.trim();
+		if attr.is_empty() { continue; }
+		let (key, value) = match attr.find('=') {
+			Some(eq) if !attr[..eq].contains(' ') =>
+				(&attr[..eq], &attr[eq + 1..]),
+			_ => match attr.find(' ') {
+				Some(space) => (&attr[..space], attr[space + 1..].trim()),
+				None => continue
+			}
+		};
+		let value = value.trim_matches('"');
+		attributes.entry(key.to_string())
+			.and_modify(|v| { v.push(','); v.push_str(value); })
+			.or_insert_with(|| value.to_string());
+	}
+	attributes
+}
+
+// Removes the type prefix (e.g. "transcript:") that Ensembl uses in GFF3 IDs.
+fn strip_id_prefix(id: &str) -> &str {
+	match id.find(':') { Some(colon) => &id[colon + 1..], None => id }
+}
+
+fn is_canonical(attributes: &HashMap<String, String>) -> bool {
+	attributes.get("tag").map(|tags| tags.split(',').any(
+		|tag| tag == "Ensembl_canonical" || tag == "MANE_Select"))
+		.unwrap_or(false)
+}
+
+// Returns true if the file looks like a GTF or GFF3 file, based on its
+// first line that is not a comment.
+pub fn is_gene_model_file(path: &str) -> io::Result<bool> {
+	let mut file = FileReader::open(path)?;
+	let mut line = String::new();
+	while file.next_line(&mut line)? {
+		if line.starts_with('#') || line.trim().is_empty() { continue; }
+		let cols: Vec<&str> = line.trim_end().split('\t').collect();
+		return Ok(cols.len() >= 9 && cols[3].parse::<u32>().is_ok() &&
+			cols[4].parse::<u32>().is_ok() &&
+			(cols[6] == "+" || cols[6] == "-" || cols[6] == "."));
+	}
+	Ok(false)
+}
+
+// Reads transcript models from a GTF or GFF3 file. Transcripts are assembled
+// from their exon and CDS lines. In GTF files these are grouped by the
+// transcript_id attribute, and in GFF3 files by the Parent attribute. Gene
+// names are taken from the gene_name attribute, or from the Name of the
+// parent gene in GFF3 files.
+pub fn read_transcripts(path: &str) -> Result<Vec<Transcript>, ParseError> {
+	let mut transcripts: HashMap<String, Transcript> = HashMap::new();
+	let mut parent_gene: HashMap<String, String> = HashMap::new();
+	let mut gene_names: HashMap<String, String> = HashMap::new();
+	let mut canonical: HashSet<String> = HashSet::new();
+
+	let io_error = |err: io::Error| ParseError::io(path, err);
+	let mut file = FileReader::open(path).map_err(io_error)?;
+	let mut line = String::new();
+	let mut line_num = 0;
+	while file.next_line(&mut line).map_err(io_error)? {
+		line_num += 1;
+		if line.starts_with('#') || line.trim().is_empty() { continue; }
+		let cols: Vec<&str> = line.trim_end().split('\t').collect();
+		let start = cols.get(3).and_then(|c| c.parse::<u32>().ok());
+		let end = cols.get(4).and_then(|c| c.parse::<u32>().ok());
+		if cols.len() < 9 || start.is_none() || end.is_none() {
+			return Err(ParseError::new(path, line_num,
+				format!("Invalid GTF/GFF3 line:\n{}", line)));
+		}
+		let (start, end) = (start.unwrap(), end.unwrap());
+		let attributes = parse_attributes(cols[8]);
+
+		// GFF3 gene and transcript lines, used for looking up gene names.
+		// Canonical transcripts may only be tagged on their transcript line.
+		if let Some(id) = attributes.get("ID") {
+			if cols[2].ends_with("gene") {
+				if let Some(name) = attributes.get("gene_name")
+					.or_else(|| attributes.get("Name")) {
+					gene_names.insert(id.clone(), name.clone());
+				}
+			}
+			if let Some(parent) = attributes.get("Parent") {
+				parent_gene.insert(id.clone(), parent.clone());
+			}
+		}
+		if is_canonical(&attributes) {
+			if let Some(id) = attributes.get("transcript_id")
+				.or_else(|| attributes.get("ID")) {
+				canonical.insert(id.clone());
+			}
+		}
+		if cols[2] != "exon" && cols[2] != "CDS" { continue; }
+
+		let id = match attributes.get("transcript_id")
+			.or_else(|| attributes.get("Parent")) {
+			Some(id) => id.split(',').next().unwrap().to_string(),
+			None => return Err(ParseError::new(path, line_num,
+				format!("Exon or CDS line without a transcript ID:\n{}", line)))
+		};
+		let transcript = transcripts.entry(id.clone()).or_insert_with(|| Transcript {
+			id: strip_id_prefix(&id).to_string(),
+			gene: attributes.get("gene_name").cloned().unwrap_or_default(),
+			chr: cols[0].to_string(), strand: cols[6] != "-",
+			canonical: false, exons: Vec::new(), cds: Vec::new()
+		});
+		if cols[2] == "exon" {
+			transcript.exons.push((start, end));
+		} else {
+			transcript.cds.push((start, end));
+		}
+	}
+
+	let mut transcripts: Vec<Transcript> = transcripts.into_iter()
+		.filter(|(_, t)| !t.exons.is_empty())
+		.map(|(key, mut t)| {
+			if canonical.contains(&key) { t.canonical = true; }
+			if t.gene.is_empty() {
+				t.gene = parent_gene.get(&key).and_then(|g| gene_names.get(g))
+					.cloned().unwrap_or_else(|| t.id.clone());
+			}
+			t.exons.sort();
+			t.cds.sort();
+			t
+		}).collect();
+	transcripts.sort_by(|a, b| (&a.chr, a.start(), &a.id).cmp(&(&b.chr, b.start(), &b.id)));
+	Ok(transcripts)
+}
+
+// Converts transcripts into gene features that span all transcripts of
+// each gene.
+pub fn gene_features(transcripts: &[Transcript]) -> Vec<Feature> {
+	let mut genes: HashMap<(&str, &str), (u32, u32, bool)> = HashMap::new();
+	for t in transcripts {
+		let span = genes.entry((&t.chr, &t.gene))
+			.or_insert((t.start(), t.end(), t.strand));
+		*span = (min(span.0, t.start()), max(span.1, t.end()), span.2);
+	}
+	let mut features: Vec<Feature> = genes.into_iter()
+		.map(|((chr, gene), (start, end, strand))| Feature {
+			chr: chr.to_string(), start, end, name: gene.to_string(),
+			strand: Some(strand)
+		}).collect();
+	features.sort_by(|a, b| (&a.chr, a.start, &a.name).cmp(&(&b.chr, b.start, &b.name)));
+	features
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn transcript(strand: bool) -> Transcript {
+		Transcript {
+			id: "T1".to_string(), gene: "A".to_string(), chr: "chr1".to_string(),
+			strand, canonical: true,
+			exons: vec![(1000, 1100), (2000, 2100), (3000, 3100)],
+			cds: vec![(1050, 1100), (2000, 2100), (3000, 3050)]
+		}
+	}
+
+	#[test]
+	fn regions_on_plus_strand() {
+		let t = transcript(true);
+		assert_eq!(t.region(1020).unwrap(), "5UTR_exon_1");
+		assert_eq!(t.region(1500).unwrap(), "intron_1");
+		assert_eq!(t.region(2050).unwrap(), "exon_2");
+		assert_eq!(t.region(2500).unwrap(), "intron_2");
+		assert_eq!(t.region(3080).unwrap(), "3UTR_exon_3");
+		assert!(t.region(999).is_none());
+	}
+
+	#[test]
+	fn regions_on_minus_strand() {
+		// Exons and introns are numbered starting from the highest coordinate
+		let t = transcript(false);
+		assert_eq!(t.region(3080).unwrap(), "5UTR_exon_1");
+		assert_eq!(t.region(2500).unwrap(), "intron_1");
+		assert_eq!(t.region(2050).unwrap(), "exon_2");
+		assert_eq!(t.region(1500).unwrap(), "intron_2");
+		assert_eq!(t.region(1020).unwrap(), "3UTR_exon_3");
+		assert!(t.region(3101).is_none());
+	}
+}
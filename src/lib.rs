@@ -0,0 +1,12 @@
+
+// Breakfast is also usable as a library. The analyses behind the detect,
+// annotate and matrix subcommands live in their own modules, and report
+// errors through their return values instead of exiting. The command line
+// parsing of the "breakfast" executable is found in its cli module. Shared
+// data structures and the readers and writers for rearrangement files are
+// found in common.
+
+pub mod common;
+pub mod align; pub mod detect; pub mod annotate;
+pub mod matrix; pub mod vcf; pub mod bedpe;
+pub mod gtf; pub mod fusion;
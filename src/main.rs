@@ -1,8 +1,8 @@
 
 use std::env;
 
-#[macro_use] mod common;
-mod detect; mod filter; mod annotate; mod blacklist; mod matrix;
+#[macro_use] mod cli;
+use cli::{detect, filter, annotate, blacklist, matrix, convert};
 
 const USAGE: &str = "
 Breakfast is a software for detecting chromosomal rearrangements in DNA/RNA
@@ -17,6 +17,7 @@ Available subcommands:
   blacklist   Construct a rearrangement blacklist based on various criteria.
   annotate    Annotate genes adjacent to rearrangement breakpoints.
   matrix      Build a read count matrix for rearrangements.
+  convert     Convert rearrangements into other file formats.
 ";
 
 fn main() {
@@ -29,6 +30,7 @@ fn main() {
 	else if args.len() >= 2 && args[1] == "annotate" { annotate::main(); }
 	else if args.len() >= 2 && args[1] == "blacklist" { blacklist::main(); }
 	else if args.len() >= 2 && args[1] == "matrix" { matrix::main(); }
+	else if args.len() >= 2 && args[1] == "convert" { convert::main(); }
 	else if args.len() == 1 { eprintln!("{}", USAGE); }
 	else { error!("Invalid subcommand.\n\n{}", USAGE); }
 }
@@ -1,26 +1,48 @@
 
-use crate::common::{parse_args, FileReader, read_bam_record};
+use crate::common::{next_bam_record, SvRecord};
+use crate::common::AlignmentReader;
 use bitvec::*;
-use rust_htslib::bam;
 use rust_htslib::bam::Record;
 use bio::alphabets::dna;
-use rayon::prelude::*;
+use std::io;
+use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::mem::swap;
+use std::cmp::{min, max};
 
-const USAGE: &str = "
-Usage:
-  breakfast matrix [options] <sv_file> <bam_files>...
+// Kinds of evidence: reads spanning the rearranged junction, and reads
+// spanning the first or second breakpoint in the reference configuration.
+// Junction spanning reads that only match the signature approximately
+// are counted separately. Junction spanning reads from aligned records
+// are also tallied separately, as a subset of all junction spanning reads.
+pub const JUNCTION: usize = 0;
+pub const REF_1: usize = 1;
+pub const REF_2: usize = 2;
+pub const APPROX: usize = 3;
+pub const ALIGNED: usize = 4;
 
-Options:
-  --threads=N         Maximum number of threads to use [default: 1]
-";
+#[derive(Clone)]
+pub struct Settings {
+	pub vaf: bool,
+	pub max_mismatches: usize,
+	pub edit_distance: bool,
+	pub count_aligned: bool,
+	pub count_duplicates: bool,
+	pub indexed: bool,
+	pub reference: String,                // Reference genome for CRAM files
+	pub regions: Vec<(String, u32, u32)>, // 0-based half-open intervals
+	pub seed_offsets: Vec<usize>
+}
 
-// Each signature is 20+20 bp, covering both sides of the breakpoint,
-// for a total of 40 bp.
+// Each signature contains --flank-len bp (20 bp by default) from both sides
+// of the breakpoint. The reference signature of each breakpoint is only
+// available if the input file contains it.
 #[derive(Debug)]
-struct Rearrangement {
-	signature: String,
-	signature_revcomp: String,
-	first_8_cols: String,
+pub struct Rearrangement {
+	pub signature: String,
+	pub signature_revcomp: String,
+	pub ref_signatures: [Option<String>; 2],
+	pub sv: SvRecord,
 	//chromosome_left: String,
 	//position_left: usize,
 	//strand_left: char,
@@ -30,7 +52,7 @@ struct Rearrangement {
 	//evidence: Vec<u32>
 }
 
-fn reverse_complement(seq: &str) -> String {
+pub fn reverse_complement(seq: &str) -> String {
 	String::from_utf8(dna::revcomp(seq.as_bytes())).unwrap()
 }
 
@@ -47,25 +69,25 @@ fn reverse_complement(seq: &str) -> String {
 // bits to indicate whether an ambiguous nucleotide was encountered.
 // This way if the hash is larger than 65535, we know that the eight
 // nucleotides used in the hash's calculation included some ambiguous ones.
-fn hash_nucleotide(hash: u32, nuc: u8) -> u32 {
+pub fn hash_nucleotide(hash: u32, nuc: u8) -> u32 {
 	((hash & 0b11111111_11111111_00111111_11111111u32) << 2) + match nuc {
 		b'A' => 0u32, b'C' => 1u32, b'G' => 2u32, b'T' => 3u32,     // ACGT
 		_ => 0b00000000_00000011_00000000_00000000u32   // Ambiguous nucleotide
 	}
 }
 
-fn hash_8bp_sequence(seq: &str) -> u32 {
+pub fn hash_8bp_sequence(seq: &str) -> Result<u32, String> {
 	assert!(seq.len() == 8);
 	let mut hash: u32 = 0;
 	for nuc in seq.bytes() { hash = hash_nucleotide(hash, nuc); }
-	if hash & 0xFFFF0000u32 > 0 { error!("Invalid sequence '{}'.", seq); }
-	hash
+	if hash & 0xFFFF0000u32 > 0 { return Err(format!("Invalid sequence '{}'.", seq)); }
+	Ok(hash)
 }
 
 // Find the most frequent element in an unsorted vector. Operates in
 // O(n log n) time.
-fn most_frequent(elems: &Vec<String>) -> String {
-	let mut sorted = elems.clone();
+pub fn most_frequent(elems: &[String]) -> String {
+	let mut sorted = elems.to_vec();
 	sorted.sort_unstable();
 	let mut most_frequent: usize = 0;
 	let mut most_frequent_count: usize = 1;
@@ -83,160 +105,285 @@ fn most_frequent(elems: &Vec<String>) -> String {
 	}
 	if curr_count > most_frequent_count {
 		most_frequent = sorted.len() - 1;
-		most_frequent_count = curr_count;
 	}
 	sorted[most_frequent].clone()
 }
 
-fn count_rearrangements(bam_path: &str, rearrangements: &Vec<Rearrangement>)
-	-> Vec<u32> {
+// Offsets of the 8 bp seeds used for finding candidate signature matches.
+// The first seed lies at the center of the signature. With N mismatches
+// allowed, we use N + 1 non-overlapping seeds, so that at least one of them
+// matches exactly. Returns an error if the signature is too short for that.
+pub fn seed_offsets(signature_len: usize, max_mismatches: usize)
+	-> Result<Vec<usize>, String> {
+	let center = signature_len / 2 - 4;
+	let mut offsets = vec![center];
+	for k in 1..signature_len / 8 + 1 {
+		if center >= 8 * k { offsets.push(center - 8 * k); }
+		if center + 8 * k + 8 <= signature_len { offsets.push(center + 8 * k); }
+	}
+	if offsets.len() < max_mismatches + 1 {
+		return Err(format!("At most {} mismatches can be allowed for {} bp signatures.",
+			offsets.len() - 1, signature_len));
+	}
+	offsets.truncate(max_mismatches + 1);
+	Ok(offsets)
+}
 
-	eprintln!("Analyzing {}...", bam_path);
+// Minimum edit distance between the signature and any substring of the
+// read sequence, computed with semi-global dynamic programming.
+fn edit_distance(seq: &[u8], signature: &[u8]) -> usize {
+	let mut prev: Vec<usize> = vec![0; seq.len() + 1];
+	let mut curr: Vec<usize> = vec![0; seq.len() + 1];
+	for i in 1..signature.len() + 1 {
+		curr[0] = i;
+		for j in 1..seq.len() + 1 {
+			let subst = prev[j - 1] + (signature[i - 1] != seq[j - 1]) as usize;
+			curr[j] = min(subst, min(prev[j], curr[j - 1]) + 1);
+		}
+		swap(&mut prev, &mut curr);
+	}
+	*prev.iter().min().unwrap()
+}
 
-	// Arrange junction signatures into a 65536-element table that is indexed
-	// with the first 8 bp of the junction signature. This allows extremely
-	// fast lookups.
-	let mut signature_exists = bitvec![0; 65536];
-	let mut signature_map: Vec<Vec<u32>> =
-		(0..65536).map(|_| Vec::new()).collect();
-	for r in 0..rearrangements.len() {
-		// Add the signature and its reverse complement to the signature map.
-		let hash = hash_8bp_sequence(&rearrangements[r].signature[16..24]);
-		signature_exists.set(hash as usize, true);
-		signature_map[hash as usize].push(r as u32);
+// Compares a signature against the read sequence, assuming that the
+// signature starts at the given read position. Returns the number of
+// differences, or None if there are more than allowed. With edit distance
+// enabled, the signature may be shifted by insertions and deletions.
+fn match_signature(seq: &[u8], start: isize, signature: &[u8],
+	settings: &Settings) -> Option<usize> {
 
-		let hash = hash_8bp_sequence(
-			&rearrangements[r].signature_revcomp[16..24]);
-		signature_exists.set(hash as usize, true);
-		signature_map[hash as usize].push(r as u32);
+	let max_diff = settings.max_mismatches;
+	if settings.edit_distance {
+		let first = max(start - max_diff as isize, 0) as usize;
+		let last = min(start + (signature.len() + max_diff) as isize,
+			seq.len() as isize);
+		if last <= first as isize { return None; }
+		let diff = edit_distance(&seq[first..last as usize], signature);
+		return if diff <= max_diff { Some(diff) } else { None };
 	}
 
-	let mut supporting_reads = vec![0; rearrangements.len()];
+	if start < 0 || start as usize + signature.len() > seq.len() {
+		return None;
+	}
+	let mut diff = 0;
+	for (k, nuc) in signature.iter().enumerate() {
+		if seq[start as usize + k] != *nuc {
+			diff += 1;
+			if diff > max_diff { return None; }
+		}
+	}
+	Some(diff)
+}
 
-	let mut bam = bam::Reader::from_path(&bam_path).unwrap_or_else(
-		|_| error!("Could not open BAM file."));
-	let mut read = Record::new();
-	while read_bam_record(&mut bam, &mut read) {
-		if read.is_unmapped() == false { continue; }
-		if read.is_duplicate() { continue; }
-		//if !count_aligned && read.is_unmapped() == false { continue; }
-		//if !count_duplicates && read.is_duplicate() { continue; }
+// Signatures of all rearrangements, arranged for fast lookups
+pub struct SignatureIndex {
+	signatures: Vec<(usize, usize, String)>,
+	signature_exists: BitVec,
+	signature_map: Vec<Vec<(u32, u32)>>
+}
+
+impl SignatureIndex {
+	// Returns an error if a signature contains other nucleotides than ACGT.
+	pub fn new(rearrangements: &[Rearrangement], settings: &Settings)
+		-> Result<SignatureIndex, String> {
 
-		let seq = String::from_utf8(read.seq().as_bytes()).unwrap();
+		// Collect all signatures that we search for, along with their reverse
+		// complements. Each signature is tagged with the index of its
+		// rearrangement, and the kind of evidence it represents.
+		let mut signatures: Vec<(usize, usize, String)> = Vec::new();
+		for (r, rearrangement) in rearrangements.iter().enumerate() {
+			signatures.push((r, JUNCTION, rearrangement.signature.clone()));
+			signatures.push((r, JUNCTION, rearrangement.signature_revcomp.clone()));
+			if !settings.vaf { continue; }
+			for (k, ref_signature) in rearrangement.ref_signatures.iter().enumerate() {
+				let ref_signature = match ref_signature { Some(s) => s, None => continue };
+				signatures.push((r, REF_1 + k, ref_signature.clone()));
+				signatures.push((r, REF_1 + k, reverse_complement(ref_signature)));
+			}
+		}
+
+		// Arrange signature seeds into a 65536-element table that is indexed
+		// with the 8 bp seed sequence. This allows extremely fast lookups.
+		// Each entry contains the signature index and the offset of the seed.
+		let mut signature_exists = bitvec![0; 65536];
+		let mut signature_map: Vec<Vec<(u32, u32)>> =
+			(0..65536).map(|_| Vec::new()).collect();
+		for (s, signature) in signatures.iter().enumerate() {
+			for offset in &settings.seed_offsets {
+				let hash = hash_8bp_sequence(&signature.2[*offset..*offset+8])?;
+				signature_exists.set(hash as usize, true);
+				signature_map[hash as usize].push((s as u32, *offset as u32));
+			}
+		}
+		Ok(SignatureIndex { signatures, signature_exists, signature_map })
+	}
+
+	// Searches a read for rearrangement signatures, and increments the
+	// counts of all rearrangement alleles that the read supports.
+	pub fn count_read(&self, read: &Record, settings: &Settings,
+		supporting_reads: &mut [[u32; 5]], found: &mut Vec<(usize, usize, usize)>) {
+
+		// Reads supporting the reference allele are almost always aligned,
+		// so aligned reads are always scanned when estimating allele
+		// fractions. Secondary and supplementary alignments are skipped so
+		// that each read is only counted once.
+		let aligned = !read.is_unmapped();
+		if aligned && !settings.count_aligned && !settings.vaf { return; }
+		if aligned && (read.is_secondary() || read.is_supplementary()) {
+			return;
+		}
+		if !settings.count_duplicates && read.is_duplicate() { return; }
+
+		let seq = read.seq().as_bytes();
 
 		// Start with some error bits set, so we only start checking
 		// against the signature map once we have hashed at least eight
 		// nucleotides.
 		let mut hash = 0b00000000_00000011_00000000_00000000u32;
-		'outer: for base in seq.bytes() {
-			hash = hash_nucleotide(hash, base);
+		found.clear();
+		for (pos, base) in seq.iter().enumerate() {
+			hash = hash_nucleotide(hash, *base);
 			if hash & 0xFFFF0000u32 > 0 { continue; }
-			if signature_exists[hash as usize] == false { continue; }
-			for ridx in &signature_map[hash as usize] {
-				// This read contains the 4+4 bp junction signature.
-				// Now check if the 20+20 bp junction is also found.
-				let rearrangement = &rearrangements[*ridx as usize];
-				if seq.contains(&rearrangement.signature) ||
-					seq.contains(&rearrangement.signature_revcomp) {
-					supporting_reads[*ridx as usize] += 1;
-					break 'outer;
+			if !self.signature_exists[hash as usize] { continue; }
+			for (s, offset) in &self.signature_map[hash as usize] {
+				// This read contains an 8 bp seed of the signature.
+				// Now check if the full signature is also found.
+				let (r, kind, ref signature) = self.signatures[*s as usize];
+				if kind == JUNCTION && aligned && !settings.count_aligned {
+					continue;
+				}
+				let start = pos as isize - 7 - *offset as isize;
+				if let Some(diff) = match_signature(&seq, start,
+					signature.as_bytes(), settings) {
+					found.push((r, kind, diff));
 				}
 			}
 		}
+
+		// Each read is counted at most once for each rearrangement allele,
+		// based on its best match.
+		found.sort_unstable();
+		found.dedup_by_key(|m| (m.0, m.1));
+		for (r, kind, diff) in found.iter() {
+			if *kind == JUNCTION && aligned { supporting_reads[*r][ALIGNED] += 1; }
+			let kind = if *kind == JUNCTION && *diff > 0 { APPROX } else { *kind };
+			supporting_reads[*r][kind] += 1;
+		}
 	}
-	return supporting_reads
 }
 
-pub fn main() {
-	let args = parse_args(USAGE);
-	let sv_path = args.get_str("<sv_file>");
-	let bam_paths = args.get_vec("<bam_files>");
-	let threads: usize = args.get_str("--threads").parse().unwrap();
-	//let count_duplicates = args.get_bool("--count-duplicates");
-	//let count_aligned = args.get_bool("--count-aligned");
-
-	// Convert BAM paths to sample names
-	let mut samples: Vec<String> = Vec::new();
-	for s in 0..bam_paths.len() {
-		let start = if let Some(slash) =
-			bam_paths[s].find('/') { slash + 1 } else { 0 };
-		let end = if bam_paths[s].ends_with(".bam") {
-			bam_paths[s].len() - 4 } else { bam_paths[s].len() };
-		samples.push(bam_paths[s][start..end].into());
-	}
-
-	let mut line = String::new();
-	let mut rearrangements: Vec<Rearrangement> = Vec::new();
-
-	// Read all rearrangement signatures into memory
-	let mut skipped_ambiguous = 0;
-	let mut sv_file = FileReader::new(&sv_path);
-	while sv_file.read_line(&mut line) {
-		if line.starts_with("CHROM\t") { continue; }
-		let mut signatures: Vec<String> = Vec::new();
-		let cols: Vec<&str> = line.split('\t').collect();
-		if cols.len() < 9 { continue; }
-		let reads: Vec<&str> = cols[8].split(';').collect();
-		for read in reads {
-			let pipe = read.find('|').unwrap();
-			if pipe < 20 { continue; }
-			signatures.push(format!("{}{}",
-				&read[pipe-20..pipe], &read[pipe+1..pipe+21]));
-		}
+// Counts the reads supporting each allele of the given rearrangements in a
+// BAM or CRAM file.
+pub fn count_rearrangements(bam_path: &str, rearrangements: &[Rearrangement],
+	settings: &Settings) -> io::Result<Vec<[u32; 5]>> {
 
-		let mut signature = most_frequent(&signatures);
-		signature.make_ascii_uppercase();
-		if signature.chars().any(
-			|b| b != 'A' && b != 'C' && b != 'G' && b != 'T') {
-			eprintln!("WARNING: Skipping the following rearrangement because its consensus signature contains ambiguous nucleotides:\n{}", line);
-			skipped_ambiguous += 1;
-			continue;
-		}
-		let signature_revcomp = reverse_complement(&signature);
+	eprintln!("Analyzing {}...", bam_path);
 
-		let first_8_cols = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
-			cols[0], cols[1], cols[2], cols[3], cols[4], cols[5],
-			cols[6], cols[7]);
+	let index = SignatureIndex::new(rearrangements, settings)
+		.map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+	let mut supporting_reads = vec![[0; 5]; rearrangements.len()];
+	let mut found: Vec<(usize, usize, usize)> = Vec::new();
+	let mut read = Record::new();
 
-		rearrangements.push(Rearrangement {
-			signature, signature_revcomp, first_8_cols
-		});
+	if !settings.indexed {
+		let mut bam = AlignmentReader::open(bam_path, &settings.reference)?;
+		while next_bam_record(&mut bam, &mut read)? {
+			index.count_read(&read, settings, &mut supporting_reads, &mut found);
+		}
+		return Ok(supporting_reads);
 	}
-	if skipped_ambiguous > 0 {
-		eprintln!("WARNING: Skipped {} rearrangements with signatures containing ambiguous nucleotides.", skipped_ambiguous);
+
+	let mut bam = AlignmentReader::open_indexed(bam_path, &settings.reference)?;
+
+	// Regions do not overlap, but a read can still overlap several of them.
+	// Reads extending beyond the region they were fetched from are
+	// remembered, so that each of them is counted only once.
+	let mut boundary_reads: HashSet<(i32, Vec<u8>, i32, u16)> = HashSet::new();
+	for (chr, start, end) in &settings.regions {
+		let tid = match bam.header().tid(chr.as_bytes()) {
+			Some(tid) => tid, None => continue
+		};
+		if !bam.fetch(tid as i32, *start, *end)? {
+			return Err(io::Error::other(format!("Could not fetch region {}:{}-{} from {}.",
+				chr, start + 1, end, bam_path)));
+		}
+		while next_bam_record(&mut bam, &mut read)? {
+			let crosses_boundary = read.pos() < *start as i32 || max(
+				read.cigar().end_pos().unwrap_or(0), read.pos() + 1) > *end as i32;
+			if crosses_boundary && !boundary_reads.insert(
+				(read.tid(), read.qname().to_vec(), read.pos(), read.flags())) {
+				continue;
+			}
+			index.count_read(&read, settings, &mut supporting_reads, &mut found);
+		}
 	}
 
-	rearrangements.sort_unstable_by(|a, b| a.signature.cmp(&b.signature));
-	for k in 1..rearrangements.len() {
-		if rearrangements[k - 1].signature == rearrangements[k].signature &&
-			rearrangements[k - 1].first_8_cols !=
-			rearrangements[k].first_8_cols {
-			eprintln!("WARNING: Found two distinct rearrangements with same signature {}:\n{}\n{}\n",
-				rearrangements[k].signature,
-				rearrangements[k - 1].first_8_cols,
-				rearrangements[k].first_8_cols);
+	// Unaligned reads whose mate is also unaligned have no coordinates,
+	// and are stored at the end of a sorted BAM file.
+	if bam.fetch_unplaced()? {
+		while next_bam_record(&mut bam, &mut read)? {
+			if read.tid() >= 0 { continue; }
+			index.count_read(&read, settings, &mut supporting_reads, &mut found);
 		}
 	}
-	rearrangements.dedup_by(|a, b| a.signature == b.signature);
+	Ok(supporting_reads)
+}
 
-	eprintln!("Identifying supporting reads for {} rearrangements in {} BAM files...", rearrangements.len(), bam_paths.len());
+// Parses the flank_len + flank_len bp core of a reference signature written
+// by "breakfast detect". Returns None if the signature is unavailable.
+pub fn parse_ref_signature(sv: &SvRecord, key: &str, flank_len: usize)
+	-> Option<String> {
+	let ref_signature = sv.note(key)?;
+	let pipe = ref_signature.find('|')?;
+	if pipe < flank_len || ref_signature.len() < pipe + 1 + flank_len {
+		return None;
+	}
+	let signature = format!("{}{}", &ref_signature[pipe-flank_len..pipe],
+		&ref_signature[pipe+1..pipe+1+flank_len]).to_ascii_uppercase();
+	if signature.chars().any(
+		|b| b != 'A' && b != 'C' && b != 'G' && b != 'T') { return None; }
+	Some(signature)
+}
 
-	rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()
-		.unwrap();
-	let evidence: Vec<Vec<u32>> = bam_paths.par_iter()
-		.map(|bam_path| count_rearrangements(&bam_path, &rearrangements))
-		.collect();
+// Determines the sample name for a BAM file. Names given in the sample sheet
+// take precedence, followed by the SM tag of the @RG header lines. If neither
+// is available, the name is derived from the file name.
+pub fn sample_name(bam_path: &str, sample_sheet: &HashMap<String, String>,
+	reference: &str) -> io::Result<String> {
+	if let Some(name) = sample_sheet.get(bam_path) { return Ok(name.clone()); }
 
-	print!("CHROM\tSTRAND\tPOSITION\tNEARBY FEATURES\t");
-	print!("CHROM\tSTRAND\tPOSITION\tNEARBY FEATURES\t");
-	print!("SUPPORTING READS\tSIGNATURE\tNOTES");
-	for sample in &samples { print!("\t{}", sample); }
-	println!();
-	for r in 0..rearrangements.len() {
-		print!("{}", rearrangements[r].first_8_cols);
-		print!("\t\t{}|{}\t", &rearrangements[r].signature[0..20], &rearrangements[r].signature[20..]);
-		for e in &evidence { print!("\t{}", e[r]); }
-		println!();
+	let bam = AlignmentReader::open(bam_path, reference)?;
+	let header = String::from_utf8_lossy(bam.header().as_bytes()).to_string();
+	let mut names: Vec<&str> = header.lines()
+		.filter(|line| line.starts_with("@RG\t"))
+		.filter_map(|line| line.split('\t').find(|tag| tag.starts_with("SM:")))
+		.map(|tag| &tag[3..]).collect();
+	names.sort();
+	names.dedup();
+	if names.len() > 1 {
+		eprintln!("WARNING: BAM file {} contains multiple samples, using sample name {}.", bam_path, names[0]);
 	}
+	if let Some(name) = names.first() { return Ok(name.to_string()); }
+
+	let file_name = Path::new(bam_path).file_name()
+		.map(|name| name.to_string_lossy().to_string())
+		.unwrap_or_else(|| bam_path.to_string());
+	for suffix in &[".bam", ".cram"] {
+		if file_name.ends_with(suffix) {
+			return Ok(file_name[..file_name.len() - suffix.len()].to_string());
+		}
+	}
+	Ok(file_name)
 }
 
+// Variant allele fraction, with the reference allele read count estimated
+// as the average across both breakpoints.
+pub fn allele_fraction(counts: &[u32; 5], ref_signatures: &[Option<String>; 2])
+	-> String {
+	let ref_counts: Vec<f64> = (0..2).filter(|k| ref_signatures[*k].is_some())
+		.map(|k| counts[REF_1 + k] as f64).collect();
+	if ref_counts.is_empty() { return "NA".into(); }
+	let alt = (counts[JUNCTION] + counts[APPROX]) as f64;
+	let total = alt + ref_counts.iter().sum::<f64>() / ref_counts.len() as f64;
+	if total == 0.0 { "NA".into() } else { format!("{:.3}", alt / total) }
+}
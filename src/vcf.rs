@@ -0,0 +1,123 @@
+
+use crate::common::SvRecord;
+use std::collections::HashMap;
+use std::io;
+use std::io::Write;
+
+const HEADER: &str = "##INFO=<ID=SVTYPE,Number=1,Type=String,Description=\"Type of structural variant\">
+##INFO=<ID=MATEID,Number=.,Type=String,Description=\"ID of mate breakend\">
+##INFO=<ID=EVENT,Number=1,Type=String,Description=\"ID of event associated to breakend\">
+##INFO=<ID=SVCLASS,Number=1,Type=String,Description=\"Event type (DEL, DUP, INV_TT, INV_HH or TRA)\">
+##INFO=<ID=SVSIZE,Number=1,Type=Integer,Description=\"Size of an intrachromosomal event\">
+##INFO=<ID=SR,Number=1,Type=Integer,Description=\"Number of supporting split reads\">
+##INFO=<ID=PE,Number=1,Type=Integer,Description=\"Number of supporting discordant read pairs\">
+##INFO=<ID=HOMLEN,Number=.,Type=Integer,Description=\"Length of base pair identical micro-homology at event breakpoints\">
+##INFO=<ID=HOMSEQ,Number=.,Type=String,Description=\"Sequence of base pair identical micro-homology at event breakpoints\">
+##INFO=<ID=INSLEN,Number=1,Type=Integer,Description=\"Length of non-templated sequence inserted at the junction\">
+##INFO=<ID=INSSEQ,Number=1,Type=String,Description=\"Non-templated sequence inserted at the junction\">
+##INFO=<ID=SIGNATURE,Number=1,Type=String,Description=\"Junction signature (8 bp from both flanks)\">
+##INFO=<ID=AMBIGUOUS,Number=0,Type=Flag,Description=\"Supporting reads had multiple candidate placements\">
+#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO";
+
+// Constructs the ALT allele of a breakend. The reference base is placed
+// before the brackets if our own DNA segment lies to the left of the
+// breakpoint. The brackets point in the direction in which the mate's DNA
+// segment extends from the mate breakpoint.
+fn breakend_alt(ref_base: char, own_left: bool, mate_chr: &str,
+	mate_pos: usize, mate_extends_right: bool) -> String {
+	let bracket = if mate_extends_right { '[' } else { ']' };
+	if own_left {
+		format!("{}{}{}:{}{}", ref_base, bracket, mate_chr, mate_pos, bracket)
+	} else {
+		format!("{}{}:{}{}{}", bracket, mate_chr, mate_pos, bracket, ref_base)
+	}
+}
+
+// Writes rearrangements as pairs of VCF 4.3 breakend (BND) records, sorted
+// by position. Reference bases are taken from the genome if available.
+pub fn write_vcf(out: &mut impl Write, rearrangements: &[SvRecord],
+	genome: Option<&HashMap<String, Vec<u8>>>) -> io::Result<()> {
+
+	let ref_base = |chr: &str, pos: usize| match genome {
+		Some(genome) => genome.get(chr).and_then(|seq| seq.get(pos - 1))
+			.map(|b| b.to_ascii_uppercase() as char).unwrap_or('N'),
+		None => 'N'
+	};
+
+	let mut records: Vec<(String, usize, String)> = Vec::new();
+	for (k, sv) in rearrangements.iter().enumerate() {
+		let id = format!("bnd_{}", k + 1);
+		let mut info = format!("SVTYPE=BND;EVENT={};SVCLASS={}",
+			id, sv.sv_type());
+		if let Some(size) = sv.size() { info += &format!(";SVSIZE={}", size); }
+		info += &format!(";SR={}", sv.reads.len());
+		for key in &["HOMLEN", "HOMSEQ", "INSLEN", "INSSEQ"] {
+			if let Some(value) = sv.note(key) { info += &format!(";{}={}", key, value); }
+		}
+		if !sv.signature.is_empty() {
+			info += &format!(";SIGNATURE={}", sv.signature);
+		}
+		if let Some(pairs) = sv.note("DISCORDANT_PAIRS") {
+			info += &format!(";PE={}", pairs);
+		}
+		if sv.note("AMBIGUOUS").is_some() { info += ";AMBIGUOUS"; }
+
+		let alt = breakend_alt(ref_base(&sv.chr, sv.pos), sv.strand,
+			&sv.mchr, sv.mpos, sv.mstrand);
+		records.push((sv.chr.clone(), sv.pos, format!(
+			"{}\t{}\t{}_1\t{}\t{}\t.\tPASS\tMATEID={}_2;{}", sv.chr, sv.pos,
+			id, ref_base(&sv.chr, sv.pos), alt, id, info)));
+
+		let alt = breakend_alt(ref_base(&sv.mchr, sv.mpos), !sv.mstrand,
+			&sv.chr, sv.pos, !sv.strand);
+		records.push((sv.mchr.clone(), sv.mpos, format!(
+			"{}\t{}\t{}_2\t{}\t{}\t.\tPASS\tMATEID={}_1;{}", sv.mchr, sv.mpos,
+			id, ref_base(&sv.mchr, sv.mpos), alt, id, info)));
+	}
+	records.sort_by(|a, b| (&a.0, a.1).cmp(&(&b.0, b.1)));
+
+	writeln!(out, "##fileformat=VCFv4.3")?;
+	writeln!(out, "##source=breakfast")?;
+	match genome {
+		Some(genome) => {
+			let mut contigs: Vec<(&String, &Vec<u8>)> = genome.iter().collect();
+			contigs.sort();
+			for (chr, seq) in contigs {
+				writeln!(out, "##contig=<ID={},length={}>", chr, seq.len())?;
+			}
+		},
+		None => {
+			let mut contigs: Vec<&String> = records.iter().map(|r| &r.0).collect();
+			contigs.dedup();
+			for chr in contigs { writeln!(out, "##contig=<ID={}>", chr)?; }
+		}
+	}
+	writeln!(out, "{}", HEADER)?;
+	for record in records { writeln!(out, "{}", record.2)?; }
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn info_fields() {
+		let mut sv = SvRecord::from_line(
+			"chr1\t+\t1000\t\tchr2\t-\t5000\t\tACGT|ACGT\t\tHOMLEN=2;HOMSEQ=AC").unwrap();
+		let mut out: Vec<u8> = Vec::new();
+		write_vcf(&mut out, std::slice::from_ref(&sv), None).unwrap();
+		let vcf = String::from_utf8(out).unwrap();
+		assert!(vcf.contains("##INFO=<ID=HOMLEN,"));
+		let record = vcf.lines().find(|line| line.starts_with("chr1\t")).unwrap();
+		assert!(record.ends_with(";SR=1;HOMLEN=2;HOMSEQ=AC"));
+
+		sv.signature = "ACGTACGT|ACGTACGT".to_string();
+		sv.notes = "INSLEN=3;INSSEQ=GAT".to_string();
+		let mut out: Vec<u8> = Vec::new();
+		write_vcf(&mut out, &[sv], None).unwrap();
+		let vcf = String::from_utf8(out).unwrap();
+		let record = vcf.lines().find(|line| line.starts_with("chr2\t")).unwrap();
+		assert!(record.ends_with(";SR=1;INSLEN=3;INSSEQ=GAT;SIGNATURE=ACGTACGT|ACGTACGT"));
+	}
+}